@@ -0,0 +1,280 @@
+use crate::{ConnectionState, HeldServerEvents, ServerAddress};
+use common::bevy::prelude::*;
+use common::bevy_networking_turbulence::NetworkResource;
+use common::protocol::{ClientIdentification, NetworkSync};
+use common::snapshot::SnapshotHistory;
+use std::net::SocketAddr;
+use thiserror::Error;
+
+/// Why the client could not reach the server it was pointed at.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ConnectError {
+    #[error("Could not resolve {address}: {reason}")]
+    Unresolvable {
+        address: String,
+        reason: String
+    },
+    #[error("The server at {0} is not answering")]
+    NoAnswer(SocketAddr)
+}
+
+/// Asks the client to drop whatever it is connected to and connect to the given `host:port`.
+pub struct ConnectRequest(pub String);
+
+/// Root of the connect screen, shown while the client is not connected and not about to be.
+pub struct ConnectScreen;
+
+/// The address being typed in on the connect screen.
+pub struct AddressInput(pub String);
+
+pub struct ConnectStatus;
+
+#[cfg(not(target_arch = "wasm32"))]
+fn args<'a>() -> clap::App<'a, 'a> {
+    let flag = |name: &'a str, help: &'a str| clap::Arg::with_name(name).long(name).takes_value(true).help(help);
+    clap::App::new("client")
+        .about("Bevy networking proof of concept client")
+        .arg(flag("server", "Server to connect to right away, as host:port").value_name("HOST:PORT"))
+        .arg(flag("token", "Signed token for servers that require one"))
+}
+
+/// An option given on the command line as `--name value`, like `--server host:port`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn launch_option(name: &str) -> Option<String> {
+    args().get_matches().value_of(name).map(str::to_string)
+}
+
+/// An option given in the page URL as `?name=value`, like `?server=host:port`.
+#[cfg(target_arch = "wasm32")]
+pub fn launch_option(name: &str) -> Option<String> {
+    let search = web_sys::window()?.location().search().ok()?;
+    let prefix = format!("{}=", name);
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(prefix.as_str()))
+        .map(percent_decode)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// What the connect screen offers when no server was requested.
+#[cfg(not(target_arch = "wasm32"))]
+fn default_target() -> String {
+    let ip = common::bevy_networking_turbulence::find_my_ip_address()
+        .unwrap_or_else(|| std::net::Ipv4Addr::LOCALHOST.into());
+    SocketAddr::new(ip, common::SERVER_PORT).to_string()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_target() -> String {
+    SocketAddr::new(std::net::Ipv4Addr::LOCALHOST.into(), common::SERVER_PORT).to_string()
+}
+
+/// Turns `host:port` into an address, the port defaults to `SERVER_PORT`.
+#[cfg(not(target_arch = "wasm32"))]
+fn resolve(target: &str) -> Result<SocketAddr, ConnectError> {
+    use std::net::ToSocketAddrs;
+    let unresolvable = |reason: String| ConnectError::Unresolvable { address: target.to_string(), reason };
+    let with_port = match target.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => target.to_string(),
+        _ => format!("{}:{}", target, common::SERVER_PORT),
+    };
+    with_port
+        .to_socket_addrs()
+        .map_err(|e| unresolvable(e.to_string()))?
+        .next()
+        .ok_or_else(|| unresolvable("no addresses found".to_string()))
+}
+
+/// Browsers cannot look up host names for us, only literal addresses work.
+#[cfg(target_arch = "wasm32")]
+fn resolve(target: &str) -> Result<SocketAddr, ConnectError> {
+    target
+        .parse::<SocketAddr>()
+        .or_else(|_| target.parse().map(|ip| SocketAddr::new(ip, common::SERVER_PORT)))
+        .map_err(|_| ConnectError::Unresolvable {
+            address: target.to_string(),
+            reason: "expected an ip:port address".to_string(),
+        })
+}
+
+/// Connects to the requested server, starting over with a fresh identity and world.
+pub fn connect_to_server(
+    mut commands: Commands,
+    mut requests: EventReader<ConnectRequest>,
+    mut net: ResMut<NetworkResource>,
+    mut state: ResMut<ConnectionState>,
+    mut history: ResMut<SnapshotHistory>,
+    mut held: ResMut<HeldServerEvents>,
+    mut identity: ResMut<ClientIdentification>,
+    synced: Query<Entity, With<NetworkSync>>,
+) {
+    let target = match requests.iter().last() {
+        Some(ConnectRequest(target)) => target.trim(),
+        None => return,
+    };
+    let address = match resolve(target) {
+        Ok(address) => address,
+        Err(e) => {
+            warn!("{}", e);
+            *state = ConnectionState::Failed(e);
+            return;
+        }
+    };
+
+    let handles: Vec<_> = net.connections.keys().copied().collect();
+    for handle in handles {
+        net.disconnect(handle);
+    }
+    for entity in synced.iter() {
+        commands.entity(entity).despawn();
+    }
+    *history = SnapshotHistory::default();
+    held.0.clear();
+    *identity = ClientIdentification::default();
+
+    info!("Connecting to address {}", address);
+    net.connect(address);
+    commands.insert_resource(ServerAddress(address));
+    *state = ConnectionState::Connecting;
+}
+
+pub fn setup_connect_screen(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut requests: EventWriter<ConnectRequest>,
+) {
+    let font = asset_server.load("fonts/DejaVuSansMono.ttf");
+    let text = |value: &str, font_size: f32, color: Color| {
+        Text::with_section(value, TextStyle { font: font.clone(), font_size, color }, Default::default())
+    };
+
+    let target = launch_option("server");
+    let input = target.clone().unwrap_or_else(default_target);
+    if let Some(target) = target {
+        requests.send(ConnectRequest(target));
+    }
+
+    commands.spawn_bundle(UiCameraBundle::default());
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                flex_direction: FlexDirection::ColumnReverse,
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+            material: materials.add(Color::NONE.into()),
+            ..Default::default()
+        })
+        .insert(ConnectScreen)
+        .with_children(|screen| {
+            screen.spawn_bundle(TextBundle {
+                text: text("Server address", 30.0, Color::BLACK),
+                ..Default::default()
+            });
+            screen
+                .spawn_bundle(TextBundle {
+                    text: text(&input, 30.0, Color::BLUE),
+                    ..Default::default()
+                })
+                .insert(AddressInput(input));
+            screen
+                .spawn_bundle(TextBundle {
+                    text: text("", 20.0, Color::RED),
+                    ..Default::default()
+                })
+                .insert(ConnectStatus);
+            screen.spawn_bundle(TextBundle {
+                text: text("Press Enter to connect", 20.0, Color::GRAY),
+                ..Default::default()
+            });
+        });
+}
+
+/// Edits the address while the connect screen is up, Enter asks to connect to it.
+pub fn type_address(
+    mut characters: EventReader<ReceivedCharacter>,
+    keys: Res<Input<KeyCode>>,
+    state: Res<ConnectionState>,
+    mut requests: EventWriter<ConnectRequest>,
+    mut input: Query<(&mut AddressInput, &mut Text)>,
+) {
+    let typed: String = characters.iter().map(|event| event.char).filter(|c| !c.is_control()).collect();
+    if !state.wants_server() {
+        return;
+    }
+    let (mut address, mut text) = match input.single_mut() {
+        Ok(input) => input,
+        Err(_) => return,
+    };
+    if !typed.is_empty() {
+        address.0.push_str(&typed);
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        address.0.pop();
+    }
+    if keys.just_pressed(KeyCode::Return) {
+        requests.send(ConnectRequest(address.0.clone()));
+    }
+    if address.is_changed() {
+        text.sections[0].value = address.0.clone();
+    }
+}
+
+/// Shows the connect screen with the last error while there is no server to talk to.
+pub fn show_connect_screen(
+    state: Res<ConnectionState>,
+    mut screen: Query<&mut Style, With<ConnectScreen>>,
+    mut status: Query<&mut Text, With<ConnectStatus>>,
+    mut visible: Query<&mut Visible, With<Parent>>,
+    parts: Query<&Children, With<ConnectScreen>>,
+) {
+    if !state.is_changed() {
+        return;
+    }
+    let shown = state.wants_server();
+    for mut style in screen.iter_mut() {
+        style.display = if shown { Display::Flex } else { Display::None };
+    }
+    // hidden layout nodes still get drawn
+    for children in parts.iter() {
+        for child in children.iter() {
+            if let Ok(mut visible) = visible.get_mut(*child) {
+                visible.is_visible = shown;
+            }
+        }
+    }
+    let message = match &*state {
+        ConnectionState::Failed(e) => e.to_string(),
+        ConnectionState::Disconnected(Some(reason)) => reason.to_string(),
+        _ => String::new(),
+    };
+    for mut text in status.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
+}
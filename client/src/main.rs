@@ -1,75 +1,225 @@
 use common::bevy::prelude::*;
-use common::bevy_networking_turbulence::{NetworkEvent, NetworkResource, NetworkingPlugin};
+use common::bevy_networking_turbulence::{NetworkError, NetworkEvent, NetworkResource};
 use common::events::*;
-use common::game::{GameInfo, Location, Movable, PlayerControllable};
+use common::game::{GameEnginePlugin, GameInfo, GameTick, Location, MoveOrder, Movable, PlayerControllable};
 use common::protocol::*;
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 use common::bevy::log::{Level, LogSettings};
+use common::codec::{BincodeCodec, NetworkCodec};
+use common::prediction::{reconcile_location, PendingInputs};
+use common::interpolation::{InterpolationSettings, SnapshotBuffer};
+use common::clock::{ClockSync, Pong, PING_INTERVAL};
+use common::replication::{Replicate, Replicated};
+use common::errors::DisconnectReason;
+use common::snapshot::{is_newer, SnapshotAck, SnapshotDelta, SnapshotHistory, WorldSnapshot};
+use common::errors::PlayerCommandValidationError;
+
+mod connect;
+pub use connect::{ConnectError, ConnectRequest};
+
+/// Where the client stands with the server, shown in the window title.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// Waiting for an address on the connect screen
+    ChoosingServer,
+    Failed(ConnectError),
+    Connecting,
+    /// The server accepted our hello and identified us
+    Connected,
+    /// None if the connection dropped without the server telling us why
+    Disconnected(Option<DisconnectReason>),
+}
+
+impl ConnectionState {
+    /// Lost connections are worth retrying, being sent away by the server is final.
+    pub fn should_reconnect(&self) -> bool {
+        matches!(self, ConnectionState::Disconnected(None) | ConnectionState::Disconnected(Some(DisconnectReason::Timeout)))
+    }
+
+    /// Whether the connect screen should ask for a server.
+    pub fn wants_server(&self) -> bool {
+        match self {
+            ConnectionState::ChoosingServer | ConnectionState::Failed(_) => true,
+            ConnectionState::Disconnected(_) => !self.should_reconnect(),
+            ConnectionState::Connecting | ConnectionState::Connected => false,
+        }
+    }
+}
+
+/// How long to wait after the connection dropped before trying to get back, in seconds
+const RECONNECT_INTERVAL: f64 = 2.0;
+/// Longest a server event waits for the snapshot of its tick, snapshots can get lost, in seconds
+const EVENT_HOLD_LIMIT: f64 = 0.5;
+
+/// Server events by tick and when they arrived, each is applied once the world snapshot of its tick is in.
+#[derive(Default)]
+struct HeldServerEvents(VecDeque<(Tick, f64, ServerEvent)>);
+
+impl HeldServerEvents {
+    fn hold(&mut self, tick: Tick, now: f64, event: ServerEvent) {
+        let at = self.0.iter().rposition(|(held, _, _)| !is_newer(*held, tick)).map_or(0, |index| index + 1);
+        self.0.insert(at, (tick, now, event));
+    }
+}
+
+/// When the server last pinged us. It only pings players it knows, a server that dropped us while we were
+/// not looking answers our heartbeats on a new connection, but never pings again.
+#[derive(Default)]
+struct LastServerPing(Option<f64>);
+
+pub struct ServerAddress(pub SocketAddr);
+
+/// Signed token to say hello with, from `--token` or `?token=`, for servers that require one.
+#[derive(Default)]
+pub struct PlayerToken(pub Option<String>);
+
+/// Asks to move our pointer to a location in window coordinates, sent for every click.
+pub struct PointerClick(pub Vec2);
+
+/// The server refused our command with this sequence.
+pub struct CommandRejected(pub InputSequence, pub PlayerCommandValidationError);
+
+/// Why the server refused our latest refused command, shown next to the connection state until we connect again.
+#[derive(Debug, Default)]
+pub struct LastRejection(pub Option<PlayerCommandValidationError>);
+
+/// Messages exchanged with the server since startup, heartbeats aside.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessageCounts {
+    pub sent: u64,
+    pub received: u64,
+}
 
 pub fn main() {
+    // read before anything opens a window, so bad options and --help exit right away
+    let token = PlayerToken(connect::launch_option("token"));
     let mut app = App::build();
 
-    app.add_plugins(DefaultPlugins)
-        .add_plugin(NetworkingPlugin {
-            link_conditioner: None,
-            message_flushing_strategy: Default::default(),
-            idle_timeout_ms: None,   // Some(7000),
-            auto_heartbeat_ms: None, //Some(2000),
-            heartbeats_and_timeouts_timestep_in_seconds: None,
-        })
-        .add_plugin(common::game::GameEnginePlugin::default());
+    app.add_plugins(DefaultPlugins);
 
     // when building for Web, use WebGL2 rendering
     #[cfg(target_arch = "wasm32")]
     app.add_plugin(common::bevy_webgl2::WebGL2Plugin);
 
-    app.add_startup_system(startup.system());
+    app.insert_resource(token);
+    app.add_plugin(ClientPlugin::default());
 
-    app.insert_resource(common::protocol::ClientIdentification::new(0));
     app.insert_resource(LogSettings{ filter: "".to_string(), level: Level::DEBUG });
 
-    app.add_system(capture_clicks.system())
-        .add_system(log_connectivity.system())
-        .add_system(receive_initial.system())
-        .add_system(receive_server_events.system())
-        .add_system(handle_movement_changes.system());
-
     app.run();
 }
 
-fn startup(mut commands: Commands, mut net: ResMut<NetworkResource>, info: Res<GameInfo>) {
-    network_setup(&mut net);
+/// The whole client. Headless clients go with `MinimalPlugins`, they have no window, no sprites and no
+/// connect screen, bots and tests drive them with `ConnectRequest`s and `PointerClick`s instead.
+#[derive(Default)]
+pub struct ClientPlugin {
+    pub headless: bool,
+    /// Has to match the server's, see `NetworkConfig`
+    pub network: NetworkConfig,
+}
+
+impl Plugin for ClientPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(GameEnginePlugin { settings: GameInfo { is_network_authority: false, headless: self.headless } });
+        add_networking(app, &self.network);
 
-    if info.headless {
-        warn!("Client is running headless!")
+        if self.headless {
+            warn!("Client is running headless!");
+            return;
+        }
+        app.add_startup_system(startup.system())
+            .add_startup_system(connect::setup_connect_screen.system());
+
+        app.add_system(capture_clicks.system().before("pointer_commands"))
+            .add_system(show_connection_state.system())
+            .add_system(connect::type_address.system())
+            .add_system(connect::show_connect_screen.system());
     }
+}
 
-    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
-    let address = SocketAddr::new(
-        common::bevy_networking_turbulence::find_my_ip_address().unwrap(),
-        common::SERVER_PORT,
-    );
+fn add_networking(app: &mut AppBuilder, network: &NetworkConfig) {
+    app.add_plugin(ConnectionPlugin { config: network.clone() });
 
-    info!("Connecting to address {}", address);
-    net.connect(address);
+    app.add_event::<ConnectRequest>()
+        .add_event::<CommandRejected>()
+        .add_event::<PointerClick>();
+    app.add_startup_system(setup_network.system());
+
+    app.init_resource::<PlayerToken>();
+    app.insert_resource(common::protocol::ClientIdentification::default());
+    app.insert_resource(SnapshotHistory::default());
+    app.insert_resource(ConnectionState::ChoosingServer);
+    app.insert_resource(PendingInputs::default());
+    app.insert_resource(InterpolationSettings::default());
+    app.insert_resource(ClockSync::default());
+    app.insert_resource(MessageCounts::default());
+    app.insert_resource(HeldServerEvents::default());
+    app.insert_resource(LastRejection::default());
+    app.insert_resource(LastServerPing::default());
+
+    app.add_system(send_pointer_commands.system().label("pointer_commands"))
+        .add_system(log_connectivity.system())
+        .add_system(say_hello.system())
+        .add_system(reconnect.system())
+        .add_system(connect::connect_to_server.system())
+        .add_system(receive_initial.system().label("server_meta"))
+        .add_system(notice_forgotten_connection.system().after("server_meta"))
+        .add_system(ping_server.system())
+        .add_system(receive_server_events.system())
+        .add_system(roll_back_rejected_commands.system())
+        .add_system(receive_world_snapshots.system().label("world_snapshots"))
+        .add_system(release_server_events.system().after("world_snapshots"))
+        .add_system(mark_remote_entities.system())
+        .add_system(handle_movement_changes.system().label("movement_changes"))
+        .add_system(interpolate_remote_entities.system().after("movement_changes"))
+        .add_system_to_stage(CoreStage::PostUpdate, reconcile_predicted_inputs.system().after("replication_apply"));
 }
 
-fn send_command(mut net: ResMut<NetworkResource>, command: PlayerCommand) {
+fn setup_network(mut net: ResMut<NetworkResource>) {
+    network_setup(&mut net);
+}
+
+fn startup(mut commands: Commands) {
+    commands.spawn_bundle(OrthographicCameraBundle::new_2d());
+}
+
+/// False if no connection took the command, because it had none or its channel was full.
+fn send_command(net: &mut NetworkResource, tick: Tick, command: PlayerCommand) -> bool {
     info!(
-        "Sending command {}",
-        common::serde_form::to_string(&command).unwrap()
+        "Sending command {:?} ({} bytes)",
+        command,
+        BincodeCodec::encoded_len(&command)
     );
-    net.broadcast_message(GameEvent::PlayerCommand(command));
+    let handles: Vec<_> = net.connections.keys().copied().collect();
+    let mut sent = false;
+    for handle in handles {
+        match net.send_message(handle, GameEvent::PlayerCommand(tick, command)) {
+            Ok(None) => sent = true,
+            Ok(Some(_)) => warn!("Channel to the server is full, command dropped"),
+            Err(e) => warn!("Failed to send command: {}", e),
+        }
+    }
+    sent
 }
 
-fn log_connectivity(mut reader: EventReader<NetworkEvent>) {
+fn log_connectivity(
+    mut reader: EventReader<NetworkEvent>,
+    mut state: ResMut<ConnectionState>,
+    identity: Res<ClientIdentification>,
+    address: Option<Res<ServerAddress>>,
+) {
     for event in reader.iter() {
         match event {
             NetworkEvent::Connected(handle) => {
                 info!("Connected! Handle is {}", handle)
             }
             NetworkEvent::Disconnected(handle) => {
-                warn!("Handle {} disconnected!", handle)
+                warn!("Handle {} disconnected!", handle);
+                // keep the reason if the server gave one
+                if matches!(*state, ConnectionState::Connecting | ConnectionState::Connected) {
+                    *state = ConnectionState::Disconnected(None);
+                }
             }
             NetworkEvent::Packet(handle, packet) => {
                 info!(
@@ -78,6 +228,16 @@ fn log_connectivity(mut reader: EventReader<NetworkEvent>) {
                     handle
                 )
             }
+            NetworkEvent::Error(handle, NetworkError::MissedHeartbeat) => {
+                warn!("Server on handle {} stopped answering", handle);
+                // a server that never identified us is probably not there at all
+                *state = match (&*state, &address) {
+                    (ConnectionState::Connecting, Some(address)) if !identity.is_assigned() => {
+                        ConnectionState::Failed(ConnectError::NoAnswer(address.0))
+                    }
+                    _ => ConnectionState::Disconnected(Some(DisconnectReason::Timeout)),
+                };
+            }
             NetworkEvent::Error(handle, error) => {
                 error!(handle = handle, error = ?error)
             }
@@ -85,29 +245,205 @@ fn log_connectivity(mut reader: EventReader<NetworkEvent>) {
     }
 }
 
-fn receive_initial(mut net: ResMut<NetworkResource>, mut identity: ResMut<ClientIdentification>) {
-    for (_, connection) in net.connections.iter_mut() {
+/// The server only identifies us after checking we speak the same protocol.
+/// After a reconnect the hello carries our session, so we get our player back.
+fn say_hello(
+    mut reader: EventReader<NetworkEvent>,
+    mut net: ResMut<NetworkResource>,
+    identity: Res<ClientIdentification>,
+    token: Res<PlayerToken>,
+    mut counts: ResMut<MessageCounts>,
+) {
+    for event in reader.iter() {
+        if let NetworkEvent::Connected(handle) = event {
+            let mut hello = Hello::current();
+            if identity.is_assigned() {
+                hello = hello.resuming(identity.session);
+            }
+            if let Some(token) = &token.0 {
+                hello = hello.with_token(token.clone());
+            }
+            debug!(hello = ?hello);
+            match net.send_message(*handle, MetaInformation::Hello(hello)) {
+                Ok(_) => counts.sent += 1,
+                Err(e) => error!("Failed to say hello to the server: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Tries to get back to the server after the connection dropped, kicked clients stay disconnected.
+fn reconnect(
+    mut commands: Commands,
+    mut net: ResMut<NetworkResource>,
+    mut state: ResMut<ConnectionState>,
+    mut history: ResMut<SnapshotHistory>,
+    mut held: ResMut<HeldServerEvents>,
+    address: Option<Res<ServerAddress>>,
+    synced: Query<Entity, With<NetworkSync>>,
+    time: Res<Time>,
+    mut lost_at: Local<Option<f64>>,
+) {
+    let address = match address {
+        Some(address) if state.should_reconnect() => address,
+        _ => {
+            *lost_at = None;
+            return;
+        }
+    };
+    let now = time.seconds_since_startup();
+    if now - *lost_at.get_or_insert(now) < RECONNECT_INTERVAL {
+        return;
+    }
+    *lost_at = None;
+
+    // the server sends the whole world again once we are back
+    for entity in synced.iter() {
+        commands.entity(entity).despawn();
+    }
+    *history = SnapshotHistory::default();
+    held.0.clear();
+
+    info!("Reconnecting to {}", address.0);
+    net.connect(address.0);
+    *state = ConnectionState::Connecting;
+}
+
+fn receive_initial(
+    mut net: ResMut<NetworkResource>,
+    mut identity: ResMut<ClientIdentification>,
+    mut clock: ResMut<ClockSync>,
+    mut state: ResMut<ConnectionState>,
+    time: Res<Time>,
+    tick: Res<GameTick>,
+    mut counts: ResMut<MessageCounts>,
+    mut rejection: ResMut<LastRejection>,
+    mut last_ping: ResMut<LastServerPing>,
+) {
+    let now = time.seconds_since_startup();
+    let mut kicked_by = Vec::new();
+    for (handle, connection) in net.connections.iter_mut() {
         let channels = connection.channels().unwrap();
         while let Some(info) = channels.recv::<MetaInformation>() {
+            counts.received += 1;
             match info {
                 MetaInformation::ClientIdentificationMessage(id) => {
                     identity.update(id);
+                    *state = ConnectionState::Connected;
+                    rejection.0 = None;
+                    last_ping.0 = Some(now);
                 }
                 MetaInformation::DisconnectReason(reason) => {
-                    error!("Was disconnected! {}", reason);
+                    error!("Disconnected by the server: {}", reason);
+                    *state = ConnectionState::Disconnected(Some(reason));
+                    kicked_by.push(*handle);
+                }
+                MetaInformation::Hello(_) => {
+                    warn!("Server should never say hello");
+                }
+                MetaInformation::Heartbeat => {}
+                MetaInformation::Ping(ping) => {
+                    channels.send::<MetaInformation>(MetaInformation::Pong(Pong::answer(&ping, now, tick.0)));
+                    counts.sent += 1;
+                    last_ping.0 = Some(now);
+                }
+                MetaInformation::Pong(pong) => {
+                    clock.record(&pong, now);
+                    debug!(rtt = clock.rtt, jitter = clock.jitter, offset = clock.offset);
                 }
             }
         }
     }
+    // the server drops the connection shortly after, no need to wait for it
+    for handle in kicked_by {
+        net.disconnect(handle);
+    }
 }
 
-fn receive_server_events(mut net: ResMut<NetworkResource>, mut writer: EventWriter<ServerEvent>) {
+/// Treats a server that stopped pinging us as a timeout, so we reconnect and resume our session.
+fn notice_forgotten_connection(
+    mut net: ResMut<NetworkResource>,
+    mut state: ResMut<ConnectionState>,
+    mut last_ping: ResMut<LastServerPing>,
+    config: Res<NetworkConfig>,
+    time: Res<Time>,
+) {
+    let pinged_at = match (&*state, last_ping.0) {
+        (ConnectionState::Connected, Some(pinged_at)) => pinged_at,
+        _ => return,
+    };
+    if time.seconds_since_startup() - pinged_at < PING_INTERVAL + config.idle_timeout_ms as f64 / 1000.0 {
+        return;
+    }
+    warn!("The server stopped pinging us, it must have dropped our connection");
+    let handles: Vec<_> = net.connections.keys().copied().collect();
+    for handle in handles {
+        net.disconnect(handle);
+    }
+    *state = ConnectionState::Disconnected(Some(DisconnectReason::Timeout));
+    last_ping.0 = None;
+}
+
+fn show_connection_state(
+    state: Res<ConnectionState>,
+    identity: Res<ClientIdentification>,
+    rejection: Res<LastRejection>,
+    mut windows: ResMut<Windows>,
+) {
+    if !state.is_changed() && !rejection.is_changed() {
+        return;
+    }
+    let title = match &*state {
+        ConnectionState::ChoosingServer => "Not connected".to_string(),
+        ConnectionState::Failed(e) => format!("Connection failed: {}", e),
+        ConnectionState::Connecting => "Connecting...".to_string(),
+        ConnectionState::Connected => match &rejection.0 {
+            Some(reason) => format!("Connected as {}, command rejected: {}", identity.name, reason),
+            None => format!("Connected as {}", identity.name),
+        },
+        ConnectionState::Disconnected(Some(reason)) => format!("Disconnected: {}", reason),
+        ConnectionState::Disconnected(None) => "Disconnected: connection lost".to_string(),
+    };
+    if let Some(window) = windows.get_primary_mut() {
+        window.set_title(title);
+    }
+}
+
+fn ping_server(
+    mut net: ResMut<NetworkResource>,
+    mut clock: ResMut<ClockSync>,
+    time: Res<Time>,
+    mut counts: ResMut<MessageCounts>,
+    mut last_ping: Local<f64>,
+) {
+    let now = time.seconds_since_startup();
+    if net.connections.is_empty() || now - *last_ping < PING_INTERVAL {
+        return;
+    }
+    *last_ping = now;
+    let ping = clock.next_ping(now);
+    net.broadcast_message(MetaInformation::Ping(ping));
+    counts.sent += 1;
+}
+
+fn receive_server_events(
+    mut net: ResMut<NetworkResource>,
+    mut held: ResMut<HeldServerEvents>,
+    mut rejections: EventWriter<CommandRejected>,
+    mut counts: ResMut<MessageCounts>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
     for (_, conn) in net.connections.iter_mut() {
         let channels = conn.channels().unwrap();
         while let Some(event) = channels.recv::<GameEvent>() {
+            counts.received += 1;
             match event {
-                GameEvent::ServerUpdate(e) => {
-                    writer.send(e);
+                GameEvent::ServerUpdate(tick, e) => {
+                    held.hold(tick, now, e);
+                }
+                GameEvent::CommandRejected(sequence, reason) => {
+                    rejections.send(CommandRejected(sequence, reason));
                 }
                 _ => {}
             }
@@ -115,32 +451,191 @@ fn receive_server_events(mut net: ResMut<NetworkResource>, mut writer: EventWrit
     }
 }
 
+/// Applies held server events up to the tick of the newest world snapshot, so they line up with the world.
+fn release_server_events(
+    mut held: ResMut<HeldServerEvents>,
+    mut writer: EventWriter<ServerEvent>,
+    history: Res<SnapshotHistory>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let snapshot_tick = history.latest().map(|snapshot| snapshot.tick);
+    while let Some((tick, received_at, _)) = held.0.front() {
+        let caught_up = snapshot_tick.map_or(false, |snapshot_tick| !is_newer(*tick, snapshot_tick));
+        if !caught_up && now - received_at < EVENT_HOLD_LIMIT {
+            break;
+        }
+        if let Some((_, _, event)) = held.0.pop_front() {
+            writer.send(event);
+        }
+    }
+}
+
+/// Undoes the prediction of refused commands and keeps the reason for `show_connection_state`.
+fn roll_back_rejected_commands(
+    mut rejections: EventReader<CommandRejected>,
+    mut pending: ResMut<PendingInputs>,
+    mut query: Query<(&mut Movable, &PlayerControllable)>,
+    identity: Res<ClientIdentification>,
+    mut last: ResMut<LastRejection>,
+) {
+    for CommandRejected(sequence, reason) in rejections.iter() {
+        warn!("Command {} rejected: {}", sequence, reason);
+        if !pending.reject(*sequence) {
+            continue;
+        }
+        if let Some((mut movable, _)) = query.iter_mut().find(|(_, control)| control.owner == identity.player_id) {
+            pending.rollback(&mut movable);
+        }
+        last.0 = Some(reason.clone());
+    }
+}
+
+/// Rebuilds world snapshots from the server's deltas, raises `EntityLocation` for whatever moved
+/// since the previous snapshot and acknowledges the newest one.
+fn receive_world_snapshots(
+    mut net: ResMut<NetworkResource>,
+    mut history: ResMut<SnapshotHistory>,
+    mut writer: EventWriter<ServerEvent>,
+    mut counts: ResMut<MessageCounts>,
+) {
+    for (_, conn) in net.connections.iter_mut() {
+        let channels = conn.channels().unwrap();
+        let mut newest = None;
+        while let Some(delta) = channels.recv::<SnapshotDelta>() {
+            counts.received += 1;
+            if let Some(latest) = history.latest() {
+                if !is_newer(delta.tick, latest.tick) {
+                    continue;
+                }
+            }
+            let baseline = match delta.baseline {
+                Some(tick) => match history.get(tick) {
+                    Some(baseline) => Some(baseline),
+                    None => {
+                        debug!("Dropping snapshot {}, baseline {} is gone", delta.tick, tick);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let snapshot = WorldSnapshot::from_delta(baseline, &delta);
+            for (id, location) in snapshot.changed_since(history.latest()) {
+                writer.send(ServerEvent::EntityLocation(NetworkSync { unique_id: id }, location));
+            }
+            newest = Some(snapshot.tick);
+            history.push(snapshot);
+        }
+        if let Some(tick) = newest {
+            channels.send::<SnapshotAck>(SnapshotAck(tick));
+            channels.flush::<SnapshotAck>();
+            counts.sent += 1;
+        }
+    }
+}
+
 fn handle_movement_changes(
     mut events: EventReader<ServerEvent>,
-    mut query: Query<(&NetworkSync, &mut Movable, &mut Location)>,
+    mut query: Query<(&mut Location, &PlayerControllable, Option<&mut SnapshotBuffer>)>,
+    entities: Res<NetworkEntityMap>,
+    identity: Res<ClientIdentification>,
+    pending: Res<PendingInputs>,
+    history: Res<SnapshotHistory>,
+    clock: Res<ClockSync>,
+    time: Res<Time>,
 ) {
+    let now = time.seconds_since_startup();
     for event in events.iter() {
-        if let ServerEvent::EntityMovementChange(netsync, movable, pos) = event {
-            if let Some((_, mut current_movable, mut current_transform)) = query
-                .iter_mut()
-                .find(|unit| unit.0.unique_id == netsync.unique_id)
-            {
-                current_movable.update(*movable);
-                current_transform.x = pos.x;
-                current_transform.y = pos.y;
-            } else {
-                warn!(msg = "Movement changed but there is no corresponding netsync present", netsync = ?netsync);
+        match event {
+            ServerEvent::PlayerLeft(player_id) => {
+                info!("Player {} left the game", player_id);
+            }
+            ServerEvent::EntityLocation(netsync, pos) => {
+                let unit = entities.get(netsync.unique_id).and_then(|entity| query.get_mut(entity).ok());
+                if let Some((mut current_location, control, snapshots)) = unit {
+                    if control.owner == identity.player_id {
+                        // the server is behind us, bring its state up to now before comparing
+                        let resimulated = history
+                            .latest()
+                            .and_then(|snapshot| Some((snapshot.tick, snapshot.location(netsync.unique_id)?)))
+                            .and_then(|(from_tick, from)| {
+                                pending.resimulate(from, from_tick, clock.estimate_remote_tick(now)?)
+                            });
+                        match resimulated {
+                            // a pointer that stopped has to end up exactly where the server's did
+                            Some((movable, location)) if !movable.is_active() => current_location.0 = location,
+                            Some((_, location)) => reconcile_location(&mut current_location, location),
+                            None => reconcile_location(&mut current_location, *pos),
+                        }
+                    } else if let Some(mut snapshots) = snapshots {
+                        snapshots.push(now, *pos);
+                    } else {
+                        current_location.x = pos.x;
+                        current_location.y = pos.y;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Own pointer is predicted, after the server state got replicated put back what the server has not seen yet.
+fn reconcile_predicted_inputs(
+    mut replicated: EventReader<Replicated>,
+    mut query: Query<(&mut Movable, &PlayerControllable)>,
+    identity: Res<ClientIdentification>,
+    mut pending: ResMut<PendingInputs>,
+) {
+    for Replicated { entity, component } in replicated.iter() {
+        if let Ok((mut movable, control)) = query.get_mut(*entity) {
+            if control.owner == identity.player_id {
+                pending.acknowledge(control.last_input);
+                if *component == Movable::ID {
+                    pending.confirm(*movable);
+                    pending.rollback(&mut movable);
+                }
+            }
+        }
+    }
+}
+
+/// Pointers of other players get a snapshot buffer, the identity can arrive after the pointers do.
+fn mark_remote_entities(
+    mut commands: Commands,
+    identity: Res<ClientIdentification>,
+    pointers: Query<(Entity, &PlayerControllable, Option<&SnapshotBuffer>), With<NetworkSync>>,
+) {
+    for (entity, control, snapshots) in pointers.iter() {
+        let remote = control.owner != identity.player_id;
+        if remote && snapshots.is_none() {
+            commands.entity(entity).insert(SnapshotBuffer::default());
+        } else if !remote && snapshots.is_some() {
+            commands.entity(entity).remove::<SnapshotBuffer>();
+        }
+    }
+}
+
+fn interpolate_remote_entities(
+    mut query: Query<(&mut SnapshotBuffer, &Movable, &mut Location)>,
+    settings: Res<InterpolationSettings>,
+    time: Res<Time>,
+) {
+    let render_time = time.seconds_since_startup() - settings.delay;
+    for (mut snapshots, movable, mut location) in query.iter_mut() {
+        if let Some(sampled) = snapshots.sample(render_time, movable, settings.max_extrapolation) {
+            if sampled != location.0 {
+                location.0 = sampled;
             }
         }
     }
 }
 
+/// Turns left clicks into `PointerClick`s.
 fn capture_clicks(
-    net: ResMut<NetworkResource>,
     mouse_input: Res<Input<MouseButton>>,
     windows: Res<Windows>,
-    identity: Res<ClientIdentification>,
-    my_pointer: Query<(&NetworkSync, &PlayerControllable), With<Movable>>,
+    mut clicks: EventWriter<PointerClick>,
 ) {
     let win = windows.get_primary().expect("no primary window");
     if mouse_input.just_pressed(MouseButton::Left) {
@@ -148,16 +643,43 @@ fn capture_clicks(
             .cursor_position()
             .expect("Mouse was clicked, cursor should have position");
         info!("Click detected at {},{}", position.x, position.y);
-        if let Some(pointer) = my_pointer
-            .iter()
-            .find(|(_, ctrl)| ctrl.owner == identity.player_id)
+        clicks.send(PointerClick(position));
+    }
+}
+
+/// Moves our pointer to clicked locations right away and tells the server.
+fn send_pointer_commands(
+    mut clicks: EventReader<PointerClick>,
+    mut net: ResMut<NetworkResource>,
+    identity: Res<ClientIdentification>,
+    mut pending: ResMut<PendingInputs>,
+    tick: Res<GameTick>,
+    clock: Res<ClockSync>,
+    time: Res<Time>,
+    mut my_pointer: Query<(&NetworkSync, &PlayerControllable, &mut Movable)>,
+    state: Res<ConnectionState>,
+    mut counts: ResMut<MessageCounts>,
+) {
+    for PointerClick(position) in clicks.iter() {
+        if *state != ConnectionState::Connected {
+            continue;
+        }
+        // commands apply to the server's timeline once we know it
+        let command_tick = clock
+            .estimate_remote_tick(time.seconds_since_startup())
+            .unwrap_or(tick.0);
+        if let Some((netsync, _, mut movable)) = my_pointer
+            .iter_mut()
+            .find(|(_, ctrl, _)| ctrl.owner == identity.player_id)
         {
-            send_command(
-                net,
-                PlayerCommand::PointerMoveChange(*pointer.0, Movable::new(position)),
-            )
+            // apply locally right away, the server acknowledges the sequence later
+            let order = MoveOrder::new(*position);
+            movable.update(Movable::from(order));
+            let sequence = pending.push(Movable::from(order), command_tick);
+            if send_command(&mut net, command_tick, PlayerCommand::PointerMoveChange(*netsync, order, sequence)) {
+                counts.sent += 1;
+            }
         } else {
-            send_command(net, PlayerCommand::Ping(42));
             warn!("No pointer for this player :(")
         }
     }
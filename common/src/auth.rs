@@ -0,0 +1,116 @@
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use crate::errors::AuthError;
+use crate::events::PlayerId;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who a token was issued to. Tokens are `base64(claims json).base64(hmac-sha256 of the first part)`,
+/// so a server knowing the secret checks them without asking anyone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    pub player_id: PlayerId,
+    pub name: String,
+    /// Unix time in seconds, None for tokens that never expire
+    pub expires_at: Option<u64>,
+}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any length")
+}
+
+pub fn sign(claims: &TokenClaims, secret: &[u8]) -> String {
+    let payload = base64::encode_config(serde_json::to_vec(claims).unwrap(), base64::URL_SAFE_NO_PAD);
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+    format!("{}.{}", payload, signature)
+}
+
+/// Checks the signature and expiry of a token, `now` is unix time in seconds.
+pub fn verify(token: &str, secret: &[u8], now: u64) -> Result<TokenClaims, AuthError> {
+    let (payload, signature) = token.split_once('.').ok_or(AuthError::Malformed)?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| AuthError::Malformed)?;
+    let mut mac = mac(secret);
+    mac.update(payload.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| AuthError::BadSignature)?;
+
+    let claims = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| AuthError::Malformed)?;
+    let claims: TokenClaims = serde_json::from_slice(&claims).map_err(|_| AuthError::Malformed)?;
+    if claims.player_id == 0 {
+        return Err(AuthError::Malformed);
+    }
+    match claims.expires_at {
+        Some(expires_at) if now >= expires_at => Err(AuthError::Expired),
+        _ => Ok(claims),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn claims(expires_at: Option<u64>) -> TokenClaims {
+        TokenClaims { player_id: 42, name: "alice".to_string(), expires_at }
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let token = sign(&claims(None), SECRET);
+        assert_eq!(verify(&token, SECRET, u64::MAX), Ok(claims(None)));
+        let token = sign(&claims(Some(100)), SECRET);
+        assert_eq!(verify(&token, SECRET, 99), Ok(claims(Some(100))));
+    }
+
+    #[test]
+    fn tampered_payloads_are_refused() {
+        let token = sign(&claims(None), SECRET);
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = TokenClaims { player_id: 1, ..claims(None) };
+        let payload = base64::encode_config(serde_json::to_vec(&forged).unwrap(), base64::URL_SAFE_NO_PAD);
+        assert_eq!(verify(&format!("{}.{}", payload, signature), SECRET, 0), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn tampered_signatures_are_refused() {
+        let token = sign(&claims(None), SECRET);
+        let (payload, signature) = token.split_once('.').unwrap();
+        let mut signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).unwrap();
+        signature[0] ^= 1;
+        let signature = base64::encode_config(signature, base64::URL_SAFE_NO_PAD);
+        assert_eq!(verify(&format!("{}.{}", payload, signature), SECRET, 0), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn tokens_of_other_secrets_are_refused() {
+        let token = sign(&claims(None), b"another secret");
+        assert_eq!(verify(&token, SECRET, 0), Err(AuthError::BadSignature));
+    }
+
+    #[test]
+    fn tokens_expire_at_their_expiry() {
+        let token = sign(&claims(Some(100)), SECRET);
+        assert_eq!(verify(&token, SECRET, 100), Err(AuthError::Expired));
+        assert_eq!(verify(&token, SECRET, 101), Err(AuthError::Expired));
+    }
+
+    #[test]
+    fn malformed_tokens_are_refused() {
+        let token = sign(&claims(None), SECRET);
+        let (payload, _) = token.split_once('.').unwrap();
+        for malformed in ["", "no dot", "a.b.c", &format!("{}.not base64!", payload)] {
+            assert_eq!(verify(malformed, SECRET, 0), Err(AuthError::Malformed), "{:?}", malformed);
+        }
+        // signed, but not claims
+        let mut mac = mac(SECRET);
+        mac.update(b"bm90IGpzb24");
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        assert_eq!(verify(&format!("bm90IGpzb24.{}", signature), SECRET, 0), Err(AuthError::Malformed));
+        // player 0 is nobody
+        let nobody = sign(&TokenClaims { player_id: 0, ..claims(None) }, SECRET);
+        assert_eq!(verify(&nobody, SECRET, 0), Err(AuthError::Malformed));
+    }
+}
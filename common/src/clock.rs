@@ -0,0 +1,94 @@
+use serde::{Serialize, Deserialize};
+use crate::events::Tick;
+use crate::game::TICK_SECONDS;
+
+/// How often each side pings the other, in seconds
+pub const PING_INTERVAL: f64 = 1.0;
+
+/// Weight of a new sample in the smoothed values
+const SMOOTHING: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Ping {
+    pub id: u32,
+    pub sent_at: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Pong {
+    pub id: u32,
+    pub ping_sent_at: f64,
+    pub remote_time: f64,
+    pub remote_tick: Tick,
+}
+
+impl Pong {
+    pub fn answer(ping: &Ping, now: f64, tick: Tick) -> Self {
+        Pong {
+            id: ping.id,
+            ping_sent_at: ping.sent_at,
+            remote_time: now,
+            remote_tick: tick,
+        }
+    }
+}
+
+/// Round trip and clock offset towards the other side of a connection, all in seconds.
+/// Clients keep one as a resource, the server keeps one per connection.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    pub rtt: f64,
+    /// Round trip of the latest pong alone, `rtt` is smoothed over many
+    pub last_rtt: f64,
+    pub jitter: f64,
+    /// Remote clock minus local clock
+    pub offset: f64,
+    pub samples: u32,
+    /// Remote time at which the remote tick 0 would have happened
+    tick_epoch: f64,
+    next_ping_id: u32,
+}
+
+impl ClockSync {
+    pub fn next_ping(&mut self, now: f64) -> Ping {
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        Ping { id: self.next_ping_id, sent_at: now }
+    }
+
+    pub fn record(&mut self, pong: &Pong, now: f64) {
+        let rtt = (now - pong.ping_sent_at).max(0.0);
+        let offset = pong.remote_time + rtt / 2.0 - now;
+        let tick_epoch = pong.remote_time - pong.remote_tick as f64 * TICK_SECONDS;
+        self.last_rtt = rtt;
+
+        if self.samples == 0 {
+            self.rtt = rtt;
+            self.jitter = 0.0;
+            self.offset = offset;
+            self.tick_epoch = tick_epoch;
+        } else {
+            self.jitter += ((rtt - self.rtt).abs() - self.jitter) * SMOOTHING;
+            self.rtt += (rtt - self.rtt) * SMOOTHING;
+            self.offset += (offset - self.offset) * SMOOTHING;
+            self.tick_epoch += (tick_epoch - self.tick_epoch) * SMOOTHING;
+        }
+        self.samples += 1;
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.samples > 0
+    }
+
+    pub fn remote_time(&self, now: f64) -> f64 {
+        now + self.offset
+    }
+
+    /// The tick the remote side is simulating right now, None until the first pong arrived.
+    pub fn estimate_remote_tick(&self, now: f64) -> Option<Tick> {
+        if !self.is_synced() {
+            return None;
+        }
+        let ticks = (self.remote_time(now) - self.tick_epoch) / TICK_SECONDS;
+        Some(ticks.max(0.0) as Tick)
+    }
+}
@@ -0,0 +1,138 @@
+use bevy::prelude::Vec2;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Encoding of messages into bytes, for measuring and comparing wire formats. It does not pick what goes on
+/// the wire: turbulence's typed channels serialize every message with bincode and cannot be given another
+/// codec, `BincodeCodec` matches what they send.
+pub trait NetworkCodec {
+    type Error: std::error::Error;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>, Self::Error>;
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M, Self::Error>;
+
+    fn encoded_len<M: Serialize>(message: &M) -> usize {
+        Self::encode(message).map(|bytes| bytes.len()).unwrap_or(0)
+    }
+}
+
+pub struct JsonCodec;
+
+impl NetworkCodec for JsonCodec {
+    type Error = serde_json::Error;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>, Self::Error> {
+        serde_json::to_vec(message)
+    }
+
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M, Self::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+/// Same options turbulence uses for its typed channels - varint integers, little endian.
+pub struct BincodeCodec;
+
+impl NetworkCodec for BincodeCodec {
+    type Error = bincode::Error;
+
+    fn encode<M: Serialize>(message: &M) -> Result<Vec<u8>, Self::Error> {
+        bincode::options().serialize(message)
+    }
+
+    fn decode<M: DeserializeOwned>(bytes: &[u8]) -> Result<M, Self::Error> {
+        bincode::options().deserialize(bytes)
+    }
+}
+
+/// Positions travel as fixed point integers, so that varint encoding can shrink them.
+pub const POSITION_QUANTUM: f32 = 16.0;
+
+pub fn quantize(value: f32) -> i32 {
+    (value * POSITION_QUANTUM).round() as i32
+}
+
+pub fn dequantize(value: i32) -> f32 {
+    value as f32 / POSITION_QUANTUM
+}
+
+/// Use with `#[serde(with = "crate::codec::quantized_vec2")]` on `Vec2` fields that go over the network.
+pub mod quantized_vec2 {
+    use super::*;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Vec2, serializer: S) -> Result<S::Ok, S::Error> {
+        (quantize(value.x), quantize(value.y)).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec2, D::Error> {
+        let (x, y) = <(i32, i32)>::deserialize(deserializer)?;
+        Ok(Vec2::new(dequantize(x), dequantize(y)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{GameEvent, PlayerCommand, ServerEvent};
+    use crate::game::MoveOrder;
+    use crate::protocol::NetworkSync;
+    use crate::snapshot::SnapshotDelta;
+    use serde::Deserialize;
+
+    /// Encoded length of `message`, after checking it decodes back to the same bytes.
+    fn round_trip<C: NetworkCodec, M: Serialize + DeserializeOwned>(message: &M) -> usize
+    where
+        C::Error: std::fmt::Debug,
+    {
+        let bytes = C::encode(message).unwrap();
+        assert_eq!(C::encode(&C::decode::<M>(&bytes).unwrap()).unwrap(), bytes);
+        bytes.len()
+    }
+
+    /// Sizes of `message` in json and in bincode.
+    fn sizes<M: Serialize + DeserializeOwned>(message: &M) -> (usize, usize) {
+        (round_trip::<JsonCodec, M>(message), round_trip::<BincodeCodec, M>(message))
+    }
+
+    #[test]
+    fn bincode_is_a_fraction_of_json_for_pointer_commands() {
+        let order = MoveOrder::new(Vec2::new(640.5, 360.25));
+        let command = GameEvent::PlayerCommand(3600, PlayerCommand::PointerMoveChange(NetworkSync { unique_id: 4_000_017 }, order, 42));
+        let (json, bincode) = sizes(&command);
+        // variant, 3 byte tick, variant, 5 byte id, two f32, 1 byte sequence
+        assert_eq!(bincode, 19);
+        assert!(bincode * 3 < json, "json {} bytes, bincode {} bytes", json, bincode);
+    }
+
+    #[test]
+    fn bincode_is_a_fraction_of_json_for_movement() {
+        let moved = GameEvent::ServerUpdate(3600, ServerEvent::EntityLocation(NetworkSync { unique_id: 4_000_017 }, Vec2::new(640.5, 360.25)));
+        let (json, bincode) = sizes(&moved);
+        // variant, 3 byte tick, variant, 5 byte id, two 3 byte quantized coordinates
+        assert_eq!(bincode, 16);
+        assert!(bincode * 3 < json, "json {} bytes, bincode {} bytes", json, bincode);
+
+        let snapshot = SnapshotDelta {
+            tick: 3600,
+            baseline: Some(3597),
+            changed: (0..16).map(|i| (4_000_000 + i, quantize(40.0 * i as f32), quantize(360.0))).collect(),
+            removed: vec![4_000_100],
+        };
+        let (json, bincode) = sizes(&snapshot);
+        // ids and coordinates dominate, 11 bytes per changed entity
+        assert_eq!(bincode, 188);
+        assert!(bincode * 3 < json * 2, "json {} bytes, bincode {} bytes", json, bincode);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Position(#[serde(with = "quantized_vec2")] Vec2);
+
+    #[test]
+    fn quantized_positions_round_to_the_quantum() {
+        let bytes = BincodeCodec::encode(&Position(Vec2::new(10.03, -3.5))).unwrap();
+        let Position(decoded) = BincodeCodec::decode(&bytes).unwrap();
+        assert_eq!(decoded, Vec2::new(10.0, -3.5));
+    }
+}
@@ -1,11 +1,62 @@
+use serde::{Serialize, Deserialize};
 use thiserror::Error;
 use crate::events::*;
+use crate::protocol::Hello;
 
-#[derive(Error, Debug)]
+/// Why the server refused a player command, sent back to the player along with the command's sequence.
+#[derive(Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum PlayerCommandValidationError {
     #[error("Player {attempted:?} does not own this unit owned by {owner:?}")]
     NotOwned{
         attempted: PlayerId,
         owner: PlayerId
+    },
+    #[error("Target {x},{y} is outside of the world")]
+    OutOfBounds {
+        x: f32,
+        y: f32
+    },
+    #[error("Target coordinates are not finite numbers")]
+    NotFinite,
+    #[error("More than {per_second} commands per second")]
+    RateExceeded {
+        per_second: f64
     }
-}
\ No newline at end of file
+}
+
+/// Why the server closed a connection, sent to the client right before it happens.
+#[derive(Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    #[error("Kicked by the server: {0}")]
+    Kicked(String),
+    #[error("The server is shutting down")]
+    ServerShutdown,
+    #[error("The server is full")]
+    ServerFull,
+    #[error("Protocol mismatch, client speaks {} ({:016x}) and server {} ({:016x})", client.version, client.message_types, server.version, server.message_types)]
+    VersionMismatch {
+        client: Hello,
+        server: Hello
+    },
+    #[error("Timed out")]
+    Timeout,
+    #[error("Cheating: {0}")]
+    Cheating(String),
+    #[error("Kept sending more messages than allowed")]
+    Flooding,
+    #[error("Authentication failed: {0}")]
+    Unauthenticated(AuthError)
+}
+
+/// Why the server did not accept a player token.
+#[derive(Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AuthError {
+    #[error("the server requires a token")]
+    Missing,
+    #[error("the token is malformed")]
+    Malformed,
+    #[error("the token was not signed by this server")]
+    BadSignature,
+    #[error("the token expired")]
+    Expired
+}
@@ -1,25 +1,36 @@
 use bevy::prelude::*;
 use serde::{Serialize, Deserialize};
-use crate::game::Movable;
+use crate::errors::PlayerCommandValidationError;
+use crate::game::MoveOrder;
 use crate::protocol::NetworkSync;
+use crate::replication::ComponentId;
 
 pub type PlayerId = u32;
+pub type InputSequence = u32;
+/// Fixed simulation step number, see `game::TICK_SECONDS`
+pub type Tick = u32;
 
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum GameEvent {
-    PlayerCommand(PlayerCommand),
-    ServerUpdate(ServerEvent)
+    PlayerCommand(Tick, PlayerCommand),
+    ServerUpdate(Tick, ServerEvent),
+    /// Sent only to the player whose command with this sequence was refused
+    CommandRejected(InputSequence, PlayerCommandValidationError)
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum PlayerCommand {
-    PointerMoveChange(NetworkSync, Movable),
-    Ping(u32)
+    PointerMoveChange(NetworkSync, MoveOrder, InputSequence),
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerEvent {
-    PointerSpawn(NetworkSync, PlayerId, Vec2),
-    EntityMovementChange(NetworkSync, Movable, Vec2),
-}
\ No newline at end of file
+    PointerSpawn(NetworkSync, PlayerId, #[serde(with = "crate::codec::quantized_vec2")] Vec2),
+    /// Raised on clients for every entity that moved in a received world snapshot
+    EntityLocation(NetworkSync, #[serde(with = "crate::codec::quantized_vec2")] Vec2),
+    EntityDespawn(NetworkSync),
+    PlayerLeft(PlayerId),
+    /// Bincode encoded state of a component registered through `replication::Replicate`
+    ComponentUpdate(NetworkSync, ComponentId, Vec<u8>),
+}
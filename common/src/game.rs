@@ -1,17 +1,34 @@
+use bevy::core::FixedTimestep;
 use bevy::ecs::schedule::ShouldRun;
 use bevy::prelude::*;
 use serde::{Serialize, Deserialize};
 use std::ops::{Deref, DerefMut};
-use bevy::math::Vec3Swizzles;
-use crate::events::{PlayerCommand, PlayerId, ServerEvent};
-use crate::errors::*;
+use crate::events::{InputSequence, PlayerId, ServerEvent, Tick};
 use crate::pointer::*;
 use crate::graphics::*;
+use crate::interpolation::SnapshotBuffer;
+use crate::protocol::{update_network_entity_map, NetworkEntityMap};
+use crate::replication::{FullSnapshotRequest, Replicate, Replicated, ReplicationAppExt};
 
-const POINTER_SPEED: u64 = 100;
+pub const POINTER_SPEED: u64 = 100;
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
-pub struct Location(pub Vec2);
+pub const TICKS_PER_SECOND: u32 = 60;
+pub const TICK_SECONDS: f64 = 1.0 / TICKS_PER_SECOND as f64;
+
+/// Insert before `GameEnginePlugin` to run simulation ticks only when asked for instead of as `Time` passes,
+/// tests stepping apps by hand use it. Ticks asked for run on the next update.
+#[derive(Debug, Default)]
+pub struct ManualTicks(pub u32);
+
+/// Number of simulation steps done so far, the same step length is used on clients and server.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GameTick(pub Tick);
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, StageLabel)]
+pub struct SimulationStage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Location(#[serde(with = "crate::codec::quantized_vec2")] pub Vec2);
 
 impl Location {
     pub fn to_transform(&self) -> Transform {
@@ -33,13 +50,33 @@ impl DerefMut for Location {
 }
 
 // Component definitions
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Movable {
     target_location: Location,
     active: bool,
     speed: u64
 }
 
+/// What players may ask of their pointer, only where to go. The server builds the `Movable` itself
+/// from a validated order, pointers always move at `POINTER_SPEED`.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct MoveOrder {
+    /// Not quantized, so the server validates exactly what the client sent
+    pub target: Vec2,
+}
+
+impl MoveOrder {
+    pub fn new(target: Vec2) -> Self {
+        MoveOrder { target }
+    }
+}
+
+impl From<MoveOrder> for Movable {
+    fn from(order: MoveOrder) -> Self {
+        Movable::new(order.target)
+    }
+}
+
 impl Movable {
     pub fn new(target: Vec2) -> Self {
         return Movable {
@@ -49,6 +86,10 @@ impl Movable {
         }
     }
 
+    pub fn speed(&self) -> u64 {
+        self.speed
+    }
+
     pub fn to_dumb_vec3(&self) -> Vec3 {
         Vec3::new(self.target_location.x, self.target_location.y, 0.0)
     }
@@ -58,19 +99,64 @@ impl Movable {
         self.active = new.active;
         self.target_location = new.target_location;
     }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Moves a unit at `location` on by one simulation tick, it stops once it arrives.
+    pub fn step(&mut self, location: &mut Vec2) {
+        if !self.active {
+            return;
+        }
+        let (next, arrived) = self.advance(*location, TICK_SECONDS as f32);
+        if arrived {
+            self.active = false;
+        }
+        *location = next;
+    }
+
+    pub fn stop(&mut self) {
+        self.active = false;
+    }
+
+    /// Where a unit at `from` gets to in `delta` seconds, and whether it reached the target.
+    pub fn advance(&self, from: Vec2, delta: f32) -> (Vec2, bool) {
+        let target_point = self.target_location.0;
+        let travel = delta * (self.speed as f32);
+        if from.distance(target_point) <= travel {
+            (target_point, true)
+        } else {
+            (from + (target_point - from).normalize() * travel, false)
+        }
+    }
 }
 
-#[derive(Debug)]
+impl Replicate for Movable {
+    const ID: u16 = 1;
+
+    fn apply(&mut self, update: Self) {
+        self.update(update);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PlayerControllable {
-    pub owner: PlayerId
+    pub owner: PlayerId,
+    /// Sequence of the last owner's command applied to this unit
+    pub last_input: InputSequence
 }
 
 impl PlayerControllable {
     pub fn new(owner: PlayerId) -> Self {
-        return PlayerControllable {owner};
+        return PlayerControllable {owner, last_input: 0};
     }
 }
 
+impl Replicate for PlayerControllable {
+    const ID: u16 = 2;
+}
+
 #[derive(Clone)]
 pub struct GameInfo {
     pub is_network_authority: bool,
@@ -91,12 +177,28 @@ impl Default for GameEnginePlugin {
 
 impl Plugin for GameEnginePlugin {
     fn build(&self, app: &mut AppBuilder) {
-        app.add_system(move_movable.system())
-            .add_system(handle_pointer_spawns.system());
-
+        let simulation = SystemStage::parallel()
+            .with_system(move_movable.system().label("move_movable"))
+            .with_system(advance_tick.system().label("advance_tick").after("move_movable"));
+        let simulation = if app.world().contains_resource::<ManualTicks>() {
+            simulation.with_run_criteria(run_manual_ticks.system())
+        } else {
+            simulation.with_run_criteria(FixedTimestep::step(TICK_SECONDS))
+        };
+        app.add_stage_before(CoreStage::Update, SimulationStage, simulation);
+        app.add_system(handle_pointer_spawns.system().label("pointer_spawns"))
+            .add_system(handle_entity_despawns.system())
+            .add_system_to_stage(CoreStage::PostUpdate, update_network_entity_map.system().label("network_entity_map"));
+
+        app.add_event::<FullSnapshotRequest>()
+            .add_event::<Replicated>()
+            .replicate::<Movable>()
+            .replicate::<PlayerControllable>();
+
+        // headless apps have no window or renderer to draw with
         if !self.settings.headless {
             app.add_system_set(SystemSet::new()
-                //.with_run_criteria(headless_condition.system())
+                .with_run_criteria(headless_condition.system())
                 .with_system(add_sprites_to_graphicals.system())
                 .with_system(location_to_transform.system())
             );
@@ -105,45 +207,43 @@ impl Plugin for GameEnginePlugin {
         app.add_event::<ServerEvent>();
 
         app.insert_resource::<GameInfo>(self.settings.clone());
+        app.insert_resource(GameTick::default());
+        app.insert_resource(NetworkEntityMap::default());
         // app.add_asset::<ColorMaterial>();
         info!("Included game engine plugin!")
     }
 }
 
+/// Drawing pauses while `GameInfo::headless` is set, apps built headless have no drawing systems at all.
 fn headless_condition(settings: Res<GameInfo>) -> ShouldRun {
     match settings.headless {
-        true => ShouldRun::YesAndCheckAgain,
-        false => ShouldRun::NoAndCheckAgain
+        true => ShouldRun::No,
+        false => ShouldRun::Yes
+    }
+}
+
+fn run_manual_ticks(mut ticks: ResMut<ManualTicks>) -> ShouldRun {
+    if ticks.0 == 0 {
+        return ShouldRun::No;
     }
+    ticks.0 -= 1;
+    ShouldRun::YesAndCheckAgain
 }
 
-fn move_movable(mut query: Query<(&mut Movable, &mut Location)>, time: Res<Time>) {
-    let delta = time.delta_seconds_f64() as f32;
+fn advance_tick(mut tick: ResMut<GameTick>) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+fn move_movable(mut query: Query<(&mut Movable, &mut Location), Without<SnapshotBuffer>>) {
     for (mut mv, mut location) in query.iter_mut() {
         if mv.active {
             info!(movable = ?mv, location = ?location);
-            let target_point = mv.to_dumb_vec3();
-            // info!(distance = transform.translation.distance(target_point), can_travel =  delta * (mv.speed as f32));
-            if location.distance(target_point.xy()) <= delta * (mv.speed as f32) {
-                mv.active = false;
-                location.x = target_point.x;
-                location.y = target_point.y;
-            } else {
-                let diff = (target_point.xy() - **location).normalize();
-                location.x += diff.x * delta * (mv.speed as f32);
-                location.y += diff.y * delta * (mv.speed as f32);
+            let (next, arrived) = mv.advance(location.0, TICK_SECONDS as f32);
+            location.0 = next;
+            // changed movables get replicated, only touch it once it arrives
+            if arrived {
+                mv.stop();
             }
         }
     }
 }
-
-pub fn validate_player_command(player_id: PlayerId, controllable: &PlayerControllable, command: PlayerCommand) -> Result<(), PlayerCommandValidationError> {
-    dbg!(command);
-    if player_id != controllable.owner {
-        error!("Player tried to control that, which was not controllable");
-        Err(PlayerCommandValidationError::NotOwned { attempted: player_id, owner: controllable.owner })
-    } else {
-        Ok(())
-    }
-}
-
@@ -0,0 +1,169 @@
+use std::collections::{HashSet, VecDeque};
+use crate::events::PlayerId;
+use crate::protocol::{NetworkObjectId, NetworkSync};
+
+const INDEX_BITS: u32 = 24;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+/// Seeds only move the starting index within this range, so ids stay small for varint encoding
+const SEED_RANGE: u32 = 1 << 16;
+
+/// Hands out ids made of an index (low 24 bits) and a generation (high 8 bits).
+/// Once fresh indices run out, released ones are reused in FIFO order with a bumped generation,
+/// so stale ids held by someone do not collide with the new owner.
+#[derive(Debug, Clone)]
+pub struct IdPool {
+    next_index: u32,
+    released: VecDeque<u32>,
+}
+
+impl IdPool {
+    pub fn seeded(seed: u32) -> Self {
+        IdPool {
+            next_index: 1 + seed % SEED_RANGE,
+            released: VecDeque::new(),
+        }
+    }
+
+    pub fn allocate(&mut self) -> u32 {
+        if self.next_index <= INDEX_MASK {
+            let id = self.next_index;
+            self.next_index += 1;
+            id
+        } else {
+            let released = self.released.pop_front().expect("Id space exhausted");
+            let generation = (released >> INDEX_BITS).wrapping_add(1) & 0xFF;
+            (generation << INDEX_BITS) | (released & INDEX_MASK)
+        }
+    }
+
+    pub fn release(&mut self, id: u32) {
+        self.released.push_back(id);
+    }
+
+    /// Released ids waiting to be handed out again.
+    pub fn released(&self) -> usize {
+        self.released.len()
+    }
+}
+
+/// Server owned source of every `NetworkObjectId` and `PlayerId`, clients only ever receive ids.
+/// Use a fixed seed for reproducible ids, the server seeds randomly so ids differ between runs.
+#[derive(Debug, Clone)]
+pub struct IdAllocator {
+    objects: IdPool,
+    players: IdPool,
+    /// Player ids handed out and not released yet, players with a token bring their own id instead
+    allocated_players: HashSet<PlayerId>,
+}
+
+impl IdAllocator {
+    pub fn seeded(seed: u32) -> Self {
+        IdAllocator {
+            objects: IdPool::seeded(seed),
+            players: IdPool::seeded(seed),
+            allocated_players: HashSet::new(),
+        }
+    }
+
+    pub fn network_sync(&mut self) -> NetworkSync {
+        NetworkSync { unique_id: self.objects.allocate() }
+    }
+
+    pub fn release_object(&mut self, id: NetworkObjectId) {
+        self.objects.release(id);
+    }
+
+    pub fn player_id(&mut self) -> PlayerId {
+        let id = self.players.allocate();
+        self.allocated_players.insert(id);
+        id
+    }
+
+    /// Gives back an id from `player_id`, ids that came from somewhere else are left alone.
+    pub fn release_player(&mut self, id: PlayerId) {
+        if self.allocated_players.remove(&id) {
+            self.players.release(id);
+        }
+    }
+
+    pub fn released_players(&self) -> usize {
+        self.players.released()
+    }
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        IdAllocator::seeded(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_ids_count_up() {
+        let mut pool = IdPool::seeded(41);
+        let ids: Vec<u32> = (0..5).map(|_| pool.allocate()).collect();
+        assert_eq!(ids, vec![42, 43, 44, 45, 46]);
+    }
+
+    #[test]
+    fn seeds_stay_within_the_seed_range() {
+        assert_eq!(IdPool::seeded(u32::MAX).allocate(), 1 + u32::MAX % SEED_RANGE);
+        assert!(IdPool::seeded(u32::MAX).allocate() <= SEED_RANGE);
+    }
+
+    #[test]
+    fn released_ids_come_back_with_the_next_generation() {
+        let mut pool = IdPool::seeded(0);
+        pool.next_index = INDEX_MASK;
+        let last_fresh = pool.allocate();
+        let first = 7;
+        pool.release(first);
+        pool.release(last_fresh);
+
+        let reused = pool.allocate();
+        assert_eq!(reused & INDEX_MASK, first);
+        assert_eq!(reused >> INDEX_BITS, 1);
+        assert_eq!(pool.allocate(), (1 << INDEX_BITS) | INDEX_MASK);
+
+        // and once more, with the generation wrapping at 8 bits
+        pool.release((0xFF << INDEX_BITS) | first);
+        assert_eq!(pool.allocate(), first);
+    }
+
+    #[test]
+    #[should_panic(expected = "Id space exhausted")]
+    fn running_out_of_ids_panics() {
+        let mut pool = IdPool::seeded(0);
+        pool.next_index = INDEX_MASK + 1;
+        pool.allocate();
+    }
+
+    #[test]
+    fn only_allocated_player_ids_are_released() {
+        let mut ids = IdAllocator::seeded(0);
+        let player = ids.player_id();
+        // claimed from a token, never allocated
+        ids.release_player(42_000);
+        assert_eq!(ids.released_players(), 0);
+        ids.release_player(player);
+        ids.release_player(player);
+        assert_eq!(ids.released_players(), 1);
+    }
+
+    #[test]
+    fn same_seed_same_ids() {
+        let run = |seed| {
+            let mut ids = IdAllocator::seeded(seed);
+            let first = ids.network_sync().unique_id;
+            let player = ids.player_id();
+            ids.release_object(first);
+            let second = ids.network_sync().unique_id;
+            (first, player, second)
+        };
+        assert_eq!(run(1234), run(1234));
+        assert_ne!(run(1234), run(4321));
+    }
+}
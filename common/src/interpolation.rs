@@ -0,0 +1,71 @@
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use crate::game::Movable;
+
+pub struct InterpolationSettings {
+    /// How far in the past remote entities are rendered, in seconds
+    pub delay: f64,
+    /// How long to keep extrapolating past the newest snapshot before the entity freezes, in seconds
+    pub max_extrapolation: f64,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        InterpolationSettings {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub time: f64,
+    pub location: Vec2,
+}
+
+/// Server snapshots of a remote entity, its `Location` is derived from these instead of being simulated.
+#[derive(Debug, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, time: f64, location: Vec2) {
+        // snapshots arriving out of order are not worth blending in
+        if let Some(newest) = self.snapshots.back() {
+            if newest.time > time {
+                return;
+            }
+        }
+        self.snapshots.push_back(Snapshot { time, location });
+    }
+
+    /// Location of the entity at `render_time`, blended between the two surrounding snapshots.
+    /// Past the newest snapshot, the entity keeps moving along `movable` for at most `max_extrapolation` seconds.
+    pub fn sample(&mut self, render_time: f64, movable: &Movable, max_extrapolation: f64) -> Option<Vec2> {
+        // keep one snapshot older than render_time to blend from
+        while self.snapshots.len() > 1 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+
+        let from = *self.snapshots.front()?;
+        match self.snapshots.get(1) {
+            Some(to) if from.time <= render_time => {
+                let span = to.time - from.time;
+                let t = if span > 0.0 { ((render_time - from.time) / span) as f32 } else { 1.0 };
+                Some(from.location.lerp(to.location, t))
+            }
+            // render_time is still before anything we have
+            Some(_) => Some(from.location),
+            None => {
+                let late_by = (render_time - from.time).clamp(0.0, max_extrapolation);
+                if movable.is_active() {
+                    Some(movable.advance(from.location, late_by as f32).0)
+                } else {
+                    Some(from.location)
+                }
+            }
+        }
+    }
+}
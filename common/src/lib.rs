@@ -7,6 +7,15 @@ pub mod protocol;
 pub mod pointer;
 pub mod errors;
 pub mod graphics;
+pub mod codec;
+pub mod prediction;
+pub mod interpolation;
+pub mod clock;
+pub mod ids;
+pub mod replication;
+pub mod snapshot;
+pub mod validation;
+pub mod auth;
 
 #[cfg(target_arch = "wasm32")]
 pub use bevy_webgl2;
@@ -1,6 +1,6 @@
 use crate::events::{PlayerId, ServerEvent};
 use crate::game::{Movable, PlayerControllable};
-use crate::protocol::NetworkSync;
+use crate::protocol::{NetworkEntityMap, NetworkSync};
 use bevy::prelude::*;
 use crate::game::Location;
 use crate::graphics::Graphical;
@@ -35,6 +35,21 @@ pub fn handle_pointer_spawns(
     }
 }
 
+pub fn handle_entity_despawns(
+    mut commands: Commands,
+    mut reader: EventReader<ServerEvent>,
+    entities: Res<NetworkEntityMap>,
+) {
+    for event in reader.iter() {
+        if let ServerEvent::EntityDespawn(netsync) = event {
+            if let Some(entity) = entities.get(netsync.unique_id) {
+                info!("Despawning network entity {}", netsync.unique_id);
+                commands.entity(entity).despawn();
+            }
+        }
+    }
+}
+
 impl PlayerPointer {
     pub fn spawn(
         commands: &mut Commands,
@@ -0,0 +1,244 @@
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use crate::events::{InputSequence, Tick};
+use crate::game::{Location, Movable, TICKS_PER_SECOND};
+use crate::snapshot::is_newer;
+
+/// How far the predicted location may drift from the server's before it gets snapped back.
+pub const RECONCILE_TOLERANCE: f32 = 20.0;
+/// Longest stretch a re-simulation covers, past it the server state is taken as it is.
+pub const MAX_RESIMULATED_TICKS: Tick = 2 * TICKS_PER_SECOND as Tick;
+
+struct PendingInput {
+    sequence: InputSequence,
+    /// Server tick the command was stamped with
+    tick: Tick,
+    movable: Movable,
+}
+
+/// Commands the client already applied locally, but the server did not acknowledge yet.
+#[derive(Default)]
+pub struct PendingInputs {
+    last_sequence: InputSequence,
+    pending: VecDeque<PendingInput>,
+    /// The server's movable as last replicated, rejected inputs roll back onto it
+    confirmed: Option<Movable>,
+}
+
+impl PendingInputs {
+    pub fn push(&mut self, movable: Movable, tick: Tick) -> InputSequence {
+        self.last_sequence = self.last_sequence.wrapping_add(1);
+        self.pending.push_back(PendingInput { sequence: self.last_sequence, tick, movable });
+        self.last_sequence
+    }
+
+    pub fn acknowledge(&mut self, ack: InputSequence) {
+        while let Some(input) = self.pending.front() {
+            if (ack.wrapping_sub(input.sequence) as i32) >= 0 {
+                self.pending.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Forgets an input the server refused, returns false if it was not pending.
+    pub fn reject(&mut self, sequence: InputSequence) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|input| input.sequence != sequence);
+        self.pending.len() != before
+    }
+
+    pub fn confirm(&mut self, movable: Movable) {
+        self.confirmed = Some(movable);
+    }
+
+    /// Resets a predicted movable to the newest input still pending, or to the server's when none is.
+    /// Without either the movable stops where it is.
+    pub fn rollback(&self, movable: &mut Movable) {
+        match (self.pending.back(), self.confirmed) {
+            (Some(input), _) => movable.update(input.movable),
+            (None, Some(confirmed)) => *movable = confirmed,
+            (None, None) => movable.stop(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Steps the confirmed movable from the server's location `from` at `from_tick` up to `to_tick`,
+    /// applying every pending input at the tick it was stamped with, like the server does. Inputs stamped
+    /// for later are applied at the end, the movable returned always carries the newest input.
+    pub fn resimulate(&self, from: Vec2, from_tick: Tick, to_tick: Tick) -> Option<(Movable, Vec2)> {
+        let mut movable = self.confirmed?;
+        let ticks = to_tick.wrapping_sub(from_tick);
+        if ticks > MAX_RESIMULATED_TICKS {
+            return None;
+        }
+        let mut location = from;
+        let mut inputs = self.pending.iter().peekable();
+        for tick in (0..ticks).map(|offset| from_tick.wrapping_add(offset)) {
+            while let Some(input) = inputs.peek() {
+                if is_newer(input.tick, tick) {
+                    break;
+                }
+                movable.update(input.movable);
+                inputs.next();
+            }
+            movable.step(&mut location);
+        }
+        if let Some(input) = inputs.last() {
+            movable.update(input.movable);
+        }
+        Some((movable, location))
+    }
+}
+
+/// Pulls a predicted location to the authoritative one, unless the two are close enough already.
+pub fn reconcile_location(predicted: &mut Location, authoritative: Vec2) {
+    if predicted.distance(authoritative) > RECONCILE_TOLERANCE {
+        predicted.0 = authoritative;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::POINTER_SPEED;
+
+    /// Distance a pointer covers in one tick.
+    fn per_tick() -> f32 {
+        POINTER_SPEED as f32 / TICKS_PER_SECOND as f32
+    }
+
+    fn towards(x: f32) -> Movable {
+        Movable::new(Vec2::new(x, 0.0))
+    }
+
+    #[test]
+    fn acknowledged_inputs_stop_being_pending() {
+        let mut inputs = PendingInputs::default();
+        let first = inputs.push(towards(100.0), 10);
+        let second = inputs.push(towards(200.0), 11);
+        assert_eq!(second, first + 1);
+
+        inputs.acknowledge(first);
+        assert!(!inputs.is_empty());
+        inputs.acknowledge(second);
+        assert!(inputs.is_empty());
+    }
+
+    #[test]
+    fn acknowledging_survives_sequence_wrapping() {
+        let mut inputs = PendingInputs { last_sequence: InputSequence::MAX - 1, ..Default::default() };
+        let before_wrap = inputs.push(towards(100.0), 0);
+        let after_wrap = inputs.push(towards(200.0), 0);
+        assert_eq!(after_wrap, 0);
+
+        inputs.acknowledge(before_wrap);
+        assert!(!inputs.is_empty());
+        inputs.acknowledge(after_wrap);
+        assert!(inputs.is_empty());
+    }
+
+    #[test]
+    fn rollback_goes_back_to_the_newest_input_left() {
+        let mut inputs = PendingInputs::default();
+        inputs.confirm(towards(10.0));
+        inputs.push(towards(100.0), 0);
+        let rejected = inputs.push(towards(200.0), 0);
+        assert!(inputs.reject(rejected));
+        assert!(!inputs.reject(rejected));
+
+        let mut predicted = towards(200.0);
+        inputs.rollback(&mut predicted);
+        assert_eq!(predicted, towards(100.0));
+    }
+
+    #[test]
+    fn rollback_without_pending_inputs_restores_the_server_state() {
+        let mut inputs = PendingInputs::default();
+        inputs.confirm(towards(10.0));
+        let rejected = inputs.push(towards(200.0), 0);
+        inputs.reject(rejected);
+
+        let mut predicted = towards(200.0);
+        inputs.rollback(&mut predicted);
+        assert_eq!(predicted, towards(10.0));
+    }
+
+    #[test]
+    fn rollback_without_server_state_stops_the_prediction() {
+        let mut inputs = PendingInputs::default();
+        let rejected = inputs.push(towards(200.0), 0);
+        inputs.reject(rejected);
+
+        let mut predicted = towards(200.0);
+        inputs.rollback(&mut predicted);
+        assert!(!predicted.is_active());
+    }
+
+    #[test]
+    fn resimulation_applies_inputs_at_their_tick() {
+        let mut inputs = PendingInputs::default();
+        let mut idle = towards(0.0);
+        idle.stop();
+        inputs.confirm(idle);
+        inputs.push(towards(1000.0), 105);
+
+        // still for 5 ticks, then 5 ticks of movement
+        let (movable, location) = inputs.resimulate(Vec2::ZERO, 100, 110).unwrap();
+        assert!(movable.is_active());
+        assert!((location.x - 5.0 * per_tick()).abs() < 0.01, "{}", location);
+    }
+
+    #[test]
+    fn resimulation_matches_stepping_on_the_server() {
+        let mut inputs = PendingInputs::default();
+        inputs.confirm(towards(1000.0));
+        inputs.push(towards(-1000.0), 3);
+
+        let mut server = towards(1000.0);
+        let mut location = Vec2::new(50.0, 0.0);
+        for tick in 0..8 {
+            if tick == 3 {
+                server.update(towards(-1000.0));
+            }
+            server.step(&mut location);
+        }
+        assert_eq!(inputs.resimulate(Vec2::new(50.0, 0.0), 0, 8), Some((server, location)));
+    }
+
+    #[test]
+    fn inputs_stamped_for_later_only_change_the_movable() {
+        let mut inputs = PendingInputs::default();
+        inputs.confirm(towards(1.0));
+        inputs.push(towards(500.0), 40);
+
+        let (movable, location) = inputs.resimulate(Vec2::ZERO, 0, 30).unwrap();
+        assert_eq!(movable, towards(500.0));
+        assert_eq!(location, Vec2::new(1.0, 0.0));
+    }
+
+    #[test]
+    fn resimulation_stops_at_the_target() {
+        let mut inputs = PendingInputs::default();
+        inputs.confirm(towards(1.0));
+        let (movable, location) = inputs.resimulate(Vec2::ZERO, 0, 30).unwrap();
+        assert!(!movable.is_active());
+        assert_eq!(location, Vec2::new(1.0, 0.0));
+    }
+
+    #[test]
+    fn resimulation_needs_server_state_and_a_short_stretch() {
+        let mut inputs = PendingInputs::default();
+        assert_eq!(inputs.resimulate(Vec2::ZERO, 0, 10), None);
+
+        inputs.confirm(towards(100.0));
+        assert_eq!(inputs.resimulate(Vec2::ZERO, 10, 10), Some((towards(100.0), Vec2::ZERO)));
+        assert_eq!(inputs.resimulate(Vec2::ZERO, 10, 9), None);
+        assert_eq!(inputs.resimulate(Vec2::ZERO, 0, MAX_RESIMULATED_TICKS + 1), None);
+        assert!(inputs.resimulate(Vec2::ZERO, Tick::MAX - 2, 3).is_some());
+    }
+}
@@ -1,14 +1,26 @@
+use bevy::core::FixedTimestep;
 use bevy::prelude::*;
 use bevy_networking_turbulence::*;
 use serde::{Serialize, Deserialize};
 use std::time::Duration;
-use crate::events::{PlayerId};
+use bevy::utils::HashMap;
+use std::any::type_name;
+use crate::events::{GameEvent, PlayerCommand, PlayerId, ServerEvent};
+use crate::snapshot::{SnapshotAck, SnapshotDelta};
+use crate::clock::{Ping, Pong};
+use crate::codec::{BincodeCodec, NetworkCodec};
+use crate::errors::{AuthError, DisconnectReason, PlayerCommandValidationError};
+use crate::game::{Movable, MoveOrder, PlayerControllable};
+use crate::replication::Replicate;
 
+/// Bump whenever a message changes shape, peers with a different version are turned away
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Allocated by the server, see `ids::IdAllocator`
 pub type NetworkObjectId = u32;
 
-pub fn generate_network_id() -> NetworkObjectId {
-    crate::get_random()
-}
+/// Secret handed to a client with its identification, presenting it again after a reconnect resumes the session
+pub type SessionToken = u64;
 
 const GAME_EVENT_CHANNEL_SETTINGS: MessageChannelSettings = MessageChannelSettings {
     channel: 0,
@@ -26,7 +38,8 @@ const GAME_EVENT_CHANNEL_SETTINGS: MessageChannelSettings = MessageChannelSettin
             rtt_resend_factor: 1.5,
         },
         max_message_len: 1024 },
-    message_buffer_size: 8,
+    // late joiners get a burst of spawns and replicated components
+    message_buffer_size: 128,
     packet_buffer_size: 8
 };
 
@@ -50,27 +63,154 @@ const META_CHANNEL_SETTINGS: MessageChannelSettings = MessageChannelSettings {
     packet_buffer_size: 8
 };
 
+const SNAPSHOT_CHANNEL_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 2,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64
+};
+
+const SNAPSHOT_ACK_CHANNEL_SETTINGS: MessageChannelSettings = MessageChannelSettings {
+    channel: 3,
+    channel_mode: MessageChannelMode::Unreliable,
+    message_buffer_size: 64,
+    packet_buffer_size: 64
+};
+
+/// Heartbeat and idle timeout settings, shared so both sides agree on when a peer is gone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Peers we have not heard from for this long get disconnected
+    pub idle_timeout_ms: usize,
+    /// Sent when nothing else was sent for this long, has to stay well below `idle_timeout_ms`
+    pub heartbeat_ms: usize,
+    /// How often timeouts are checked and heartbeats sent, in seconds
+    pub check_interval: f64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            idle_timeout_ms: 7000,
+            heartbeat_ms: 2000,
+            check_interval: 0.5,
+        }
+    }
+}
+
+/// `NetworkingPlugin` set up from a `NetworkConfig`, plus our own heartbeats.
+/// The plugin's heartbeats are raw packets, which panic once message channels are registered.
+#[derive(Default)]
+pub struct ConnectionPlugin {
+    pub config: NetworkConfig,
+}
+
+impl Plugin for ConnectionPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        app.add_plugin(NetworkingPlugin {
+            link_conditioner: None,
+            message_flushing_strategy: Default::default(),
+            idle_timeout_ms: Some(self.config.idle_timeout_ms),
+            auto_heartbeat_ms: None,
+            heartbeats_and_timeouts_timestep_in_seconds: Some(self.config.check_interval),
+        })
+        .insert_resource(self.config.clone())
+        .add_system(send_heartbeats.system().with_run_criteria(FixedTimestep::step(self.config.check_interval)));
+    }
+}
+
+fn send_heartbeats(mut net: ResMut<NetworkResource>, config: Res<NetworkConfig>) {
+    for (_, connection) in net.connections.iter_mut() {
+        let (_, since_sent) = connection.last_packet_timings();
+        if since_sent > config.heartbeat_ms as u128 {
+            if let Some(channels) = connection.channels() {
+                channels.send::<MetaInformation>(MetaInformation::Heartbeat);
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MetaInformation {
+    /// First message of every client, the server answers with an identification or a disconnect reason
+    Hello(Hello),
     ClientIdentificationMessage(ClientIdentification),
-    DisconnectReason(String)
+    DisconnectReason(DisconnectReason),
+    Ping(Ping),
+    Pong(Pong),
+    /// Keeps an otherwise quiet connection from timing out, see `NetworkConfig`
+    Heartbeat
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// See `message_types_hash`
+    pub message_types: u64,
+    /// Token of the session the client wants to resume, None for a new player
+    pub session: Option<SessionToken>,
+    /// Signed player token, see `auth`. Servers with an auth secret turn away clients without one
+    pub token: Option<String>,
+}
+
+impl Hello {
+    pub fn current() -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            message_types: message_types_hash(),
+            session: None,
+            token: None,
+        }
+    }
+
+    pub fn resuming(self, session: SessionToken) -> Self {
+        Hello { session: Some(session), ..self }
+    }
+
+    pub fn with_token(self, token: String) -> Self {
+        Hello { token: Some(token), ..self }
+    }
+
+    /// Whether a client sending `self` can talk to this build of the server.
+    pub fn check(&self) -> Result<(), DisconnectReason> {
+        let ours = Hello::current();
+        if self.version == ours.version && self.message_types == ours.message_types {
+            Ok(())
+        } else {
+            // no need to send the token back
+            let client = Hello { token: None, ..self.clone() };
+            Err(DisconnectReason::VersionMismatch { client, server: ours })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ClientIdentification {
-    pub player_id: crate::events::PlayerId
+    pub player_id: crate::events::PlayerId,
+    pub session: SessionToken,
+    /// Display name, taken from the player token if the server requires one
+    pub name: String
 }
 
 impl ClientIdentification {
-    pub fn new(id: PlayerId) -> Self {
+    pub fn new(id: PlayerId, session: SessionToken, name: String) -> Self {
         Self {
-            player_id: id
+            player_id: id,
+            session,
+            name
         }
     }
 
+    /// Player ids start at 1, 0 means the server did not identify us yet
+    pub fn is_assigned(&self) -> bool {
+        self.player_id != 0
+    }
+
     pub fn update(&mut self, other: Self) {
         self.player_id = other.player_id;
+        self.session = other.session;
+        self.name = other.name;
     }
 }
 
@@ -79,14 +219,59 @@ pub struct NetworkSync {
     pub unique_id: NetworkObjectId,
 }
 
-impl NetworkSync {
-    pub fn new() -> Self {
-        NetworkSync {
-            unique_id: generate_network_id()
+/// Index from network ids to local entities, kept up to date in `CoreStage::PostUpdate`.
+/// Spawns and despawns lag by a frame: entities only exist once their commands were applied at the end of
+/// a stage, and the map learns about them in the `network_entity_map` system after that. Systems running
+/// before it in the same frame miss new entities and still find despawned ones, which then fail their
+/// queries. Order after `network_entity_map` to see the changes of the same frame.
+#[derive(Default)]
+pub struct NetworkEntityMap {
+    entities: HashMap<NetworkObjectId, Entity>,
+    ids: HashMap<Entity, NetworkObjectId>,
+}
+
+impl NetworkEntityMap {
+    pub fn get(&self, id: NetworkObjectId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    fn insert(&mut self, id: NetworkObjectId, entity: Entity) {
+        if let Some(previous) = self.entities.insert(id, entity) {
+            if previous != entity {
+                warn!("Network id {} was already taken by {:?}", id, previous);
+                self.ids.remove(&previous);
+            }
+        }
+        self.ids.insert(entity, id);
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        if let Some(id) = self.ids.remove(&entity) {
+            self.entities.remove(&id);
         }
     }
 }
 
+pub fn update_network_entity_map(
+    mut map: ResMut<NetworkEntityMap>,
+    added: Query<(Entity, &NetworkSync), Added<NetworkSync>>,
+    removed: RemovedComponents<NetworkSync>,
+) {
+    for entity in removed.iter() {
+        map.remove(entity);
+    }
+    for (entity, netsync) in added.iter() {
+        map.insert(netsync.unique_id, entity);
+    }
+}
 
 /*
 impl Display for NetworkSync {
@@ -97,13 +282,228 @@ impl Display for NetworkSync {
 
  */
 
-pub fn network_setup(net: &mut ResMut<NetworkResource>) {
+/// FNV-1a over the registered message types, their channel settings and the encoding of a sample of every
+/// message, see `message_samples`. Adding, removing or retyping a field changes the encoding of its samples,
+/// so builds that would fail to decode each other's messages disagree on the hash.
+pub fn message_types_hash() -> u64 {
+    let registered = [
+        (type_name::<GameEvent>(), &GAME_EVENT_CHANNEL_SETTINGS),
+        (type_name::<MetaInformation>(), &META_CHANNEL_SETTINGS),
+        (type_name::<SnapshotDelta>(), &SNAPSHOT_CHANNEL_SETTINGS),
+        (type_name::<SnapshotAck>(), &SNAPSHOT_ACK_CHANNEL_SETTINGS),
+    ];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut add = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    for (name, settings) in registered.iter() {
+        add(format!("{}{:?}", name, settings).as_bytes());
+    }
+    for sample in message_samples() {
+        add(&sample);
+    }
+    hash
+}
+
+/// Encoded samples of every variant of every message, and of every replicated component.
+fn message_samples() -> Vec<Vec<u8>> {
+    let snapshot = SnapshotDelta { tick: 1, baseline: Some(0), changed: vec![(1, 2, 3)], removed: vec![4] };
+    let mut samples = Vec::new();
+    samples.extend(game_event_samples().iter().map(BincodeCodec::encode));
+    samples.extend(meta_samples().iter().map(BincodeCodec::encode));
+    samples.push(BincodeCodec::encode(&snapshot));
+    samples.push(BincodeCodec::encode(&SnapshotAck(1)));
+    samples.into_iter().collect::<Result<_, _>>().expect("message samples encode")
+}
+
+fn game_event_samples() -> Vec<GameEvent> {
+    let sync = NetworkSync { unique_id: 1 };
+    let order = MoveOrder::new(Vec2::new(1.0, 2.0));
+    let component = |id, data: Result<Vec<u8>, _>| ServerEvent::ComponentUpdate(sync, id, data.expect("components encode"));
+    let updates = vec![
+        ServerEvent::PointerSpawn(sync, 2, Vec2::new(1.0, 2.0)),
+        ServerEvent::EntityLocation(sync, Vec2::new(1.0, 2.0)),
+        ServerEvent::EntityDespawn(sync),
+        ServerEvent::PlayerLeft(2),
+        component(Movable::ID, BincodeCodec::encode(&Movable::new(Vec2::new(1.0, 2.0)))),
+        component(PlayerControllable::ID, BincodeCodec::encode(&PlayerControllable { owner: 2, last_input: 3 })),
+    ];
+    let rejections = vec![
+        PlayerCommandValidationError::NotOwned { attempted: 2, owner: 3 },
+        PlayerCommandValidationError::OutOfBounds { x: 1.0, y: 2.0 },
+        PlayerCommandValidationError::NotFinite,
+        PlayerCommandValidationError::RateExceeded { per_second: 1.0 },
+    ];
+    std::iter::once(GameEvent::PlayerCommand(1, PlayerCommand::PointerMoveChange(sync, order, 2)))
+        .chain(updates.into_iter().map(|update| GameEvent::ServerUpdate(1, update)))
+        .chain(rejections.into_iter().map(|reason| GameEvent::CommandRejected(1, reason)))
+        .collect()
+}
+
+fn meta_samples() -> Vec<MetaInformation> {
+    let hello = Hello { version: 1, message_types: 2, session: Some(3), token: Some("token".to_string()) };
+    let reasons = vec![
+        DisconnectReason::Kicked("reason".to_string()),
+        DisconnectReason::ServerShutdown,
+        DisconnectReason::ServerFull,
+        DisconnectReason::VersionMismatch { client: hello.clone(), server: hello.clone() },
+        DisconnectReason::Timeout,
+        DisconnectReason::Cheating("reason".to_string()),
+        DisconnectReason::Flooding,
+        DisconnectReason::Unauthenticated(AuthError::Missing),
+        DisconnectReason::Unauthenticated(AuthError::Malformed),
+        DisconnectReason::Unauthenticated(AuthError::BadSignature),
+        DisconnectReason::Unauthenticated(AuthError::Expired),
+    ];
+    vec![
+        MetaInformation::Hello(hello),
+        MetaInformation::ClientIdentificationMessage(ClientIdentification::new(1, 2, "name".to_string())),
+        MetaInformation::Ping(Ping { id: 1, sent_at: 2.0 }),
+        MetaInformation::Pong(Pong { id: 1, ping_sent_at: 2.0, remote_time: 3.0, remote_tick: 4 }),
+        MetaInformation::Heartbeat,
+    ]
+    .into_iter()
+    .chain(reasons.into_iter().map(MetaInformation::DisconnectReason))
+    .collect()
+}
+
+/// Registers the message channels, turbulence encodes all of them with bincode (see `codec::BincodeCodec`).
+pub fn network_setup(net: &mut NetworkResource) {
     net.set_channels_builder(|builder: &mut ConnectionChannelsBuilder| {
         builder
-            .register::<crate::events::GameEvent>(GAME_EVENT_CHANNEL_SETTINGS)
+            .register::<GameEvent>(GAME_EVENT_CHANNEL_SETTINGS)
             .unwrap();
         builder
             .register::<MetaInformation>(META_CHANNEL_SETTINGS)
+            .unwrap();
+        builder
+            .register::<SnapshotDelta>(SNAPSHOT_CHANNEL_SETTINGS)
+            .unwrap();
+        builder
+            .register::<SnapshotAck>(SNAPSHOT_ACK_CHANNEL_SETTINGS)
             .unwrap()
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// Variants the samples went through. The matches do not compile once a variant is added,
+    /// which is the reminder to add a sample for it.
+    fn covered_variants() -> HashSet<&'static str> {
+        let rejection = |reason: &PlayerCommandValidationError| match reason {
+            PlayerCommandValidationError::NotOwned { .. } => "NotOwned",
+            PlayerCommandValidationError::OutOfBounds { .. } => "OutOfBounds",
+            PlayerCommandValidationError::NotFinite => "NotFinite",
+            PlayerCommandValidationError::RateExceeded { .. } => "RateExceeded",
+        };
+        let update = |event: &ServerEvent| match event {
+            ServerEvent::PointerSpawn(..) => "PointerSpawn",
+            ServerEvent::EntityLocation(..) => "EntityLocation",
+            ServerEvent::EntityDespawn(..) => "EntityDespawn",
+            ServerEvent::PlayerLeft(..) => "PlayerLeft",
+            ServerEvent::ComponentUpdate(_, id, _) if *id == Movable::ID => "Movable",
+            ServerEvent::ComponentUpdate(..) => "PlayerControllable",
+        };
+        let auth = |error: &AuthError| match error {
+            AuthError::Missing => "Missing",
+            AuthError::Malformed => "Malformed",
+            AuthError::BadSignature => "BadSignature",
+            AuthError::Expired => "Expired",
+        };
+        let reason = |reason: &DisconnectReason| match reason {
+            DisconnectReason::Kicked(_) => "Kicked",
+            DisconnectReason::ServerShutdown => "ServerShutdown",
+            DisconnectReason::ServerFull => "ServerFull",
+            DisconnectReason::VersionMismatch { .. } => "VersionMismatch",
+            DisconnectReason::Timeout => "Timeout",
+            DisconnectReason::Cheating(_) => "Cheating",
+            DisconnectReason::Flooding => "Flooding",
+            DisconnectReason::Unauthenticated(error) => auth(error),
+        };
+        let game_events = game_event_samples().into_iter().map(|event| match event {
+            GameEvent::PlayerCommand(_, PlayerCommand::PointerMoveChange(..)) => "PointerMoveChange",
+            GameEvent::ServerUpdate(_, event) => update(&event),
+            GameEvent::CommandRejected(_, error) => rejection(&error),
+        });
+        let meta = meta_samples().into_iter().map(|info| match info {
+            MetaInformation::Hello(_) => "Hello",
+            MetaInformation::ClientIdentificationMessage(_) => "ClientIdentificationMessage",
+            MetaInformation::DisconnectReason(r) => reason(&r),
+            MetaInformation::Ping(_) => "Ping",
+            MetaInformation::Pong(_) => "Pong",
+            MetaInformation::Heartbeat => "Heartbeat",
+        });
+        game_events.chain(meta).collect()
+    }
+
+    #[test]
+    fn samples_cover_every_message_variant() {
+        let samples = game_event_samples().len() + meta_samples().len();
+        let covered = covered_variants();
+        assert_eq!(covered.len(), samples, "some samples are of the same variant");
+        // 1 command, 6 server events, 4 rejections, 5 meta messages, 7 disconnect reasons and 4 auth errors
+        assert_eq!(covered.len(), 27);
+    }
+
+    #[test]
+    fn hashes_agree_within_a_build() {
+        assert_eq!(message_types_hash(), message_types_hash());
+        assert_eq!(Hello::current().check(), Ok(()));
+    }
+
+    fn map_stage() -> (World, SystemStage) {
+        let mut world = World::default();
+        world.insert_resource(NetworkEntityMap::default());
+        (world, SystemStage::parallel().with_system(update_network_entity_map.system()))
+    }
+
+    fn lookup(world: &World, id: NetworkObjectId) -> Option<Entity> {
+        world.get_resource::<NetworkEntityMap>().unwrap().get(id)
+    }
+
+    #[test]
+    fn spawned_entities_show_up_once_the_map_ran() {
+        let (mut world, mut stage) = map_stage();
+        let entity = world.spawn().insert(NetworkSync { unique_id: 7 }).id();
+        assert_eq!(lookup(&world, 7), None);
+        stage.run(&mut world);
+        world.clear_trackers();
+        assert_eq!(lookup(&world, 7), Some(entity));
+        assert_eq!(world.get_resource::<NetworkEntityMap>().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn despawned_entities_stay_until_the_map_ran() {
+        let (mut world, mut stage) = map_stage();
+        let entity = world.spawn().insert(NetworkSync { unique_id: 7 }).id();
+        stage.run(&mut world);
+        world.clear_trackers();
+
+        world.despawn(entity);
+        assert_eq!(lookup(&world, 7), Some(entity));
+        stage.run(&mut world);
+        world.clear_trackers();
+        assert_eq!(lookup(&world, 7), None);
+        assert!(world.get_resource::<NetworkEntityMap>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ids_taken_over_by_another_entity_point_to_it() {
+        let (mut world, mut stage) = map_stage();
+        let old = world.spawn().insert(NetworkSync { unique_id: 7 }).id();
+        stage.run(&mut world);
+        world.clear_trackers();
+
+        world.despawn(old);
+        let new = world.spawn().insert(NetworkSync { unique_id: 7 }).id();
+        stage.run(&mut world);
+        world.clear_trackers();
+        assert_eq!(lookup(&world, 7), Some(new));
+    }
+}
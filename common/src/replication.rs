@@ -0,0 +1,140 @@
+use bevy::ecs::component::Component;
+use bevy::prelude::*;
+use bevy_networking_turbulence::{ConnectionHandle, NetworkResource};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::codec::{BincodeCodec, NetworkCodec};
+use crate::events::{GameEvent, ServerEvent};
+use crate::game::{GameInfo, GameTick};
+use crate::protocol::{NetworkEntityMap, NetworkSync};
+
+/// Identifies a replicated component type on the wire, must be unique among replicated types.
+pub type ComponentId = u16;
+
+/// A component the server keeps in sync on every client, for entities with `NetworkSync`.
+/// Register it once with `app.replicate::<C>()`.
+pub trait Replicate: Component + Clone + Serialize + DeserializeOwned {
+    const ID: ComponentId;
+
+    /// Applies the server's state to the local component.
+    fn apply(&mut self, update: Self) {
+        *self = update;
+    }
+}
+
+/// Asks the server to send the full replicated state to a connection, send it after the spawns.
+pub struct FullSnapshotRequest(pub ConnectionHandle);
+
+/// Emitted on clients after a replicated component of `entity` was updated by the server.
+pub struct Replicated {
+    pub entity: Entity,
+    pub component: ComponentId,
+}
+
+pub trait ReplicationAppExt {
+    fn replicate<C: Replicate>(&mut self) -> &mut Self;
+}
+
+impl ReplicationAppExt for AppBuilder {
+    fn replicate<C: Replicate>(&mut self) -> &mut Self {
+        self.add_system_to_stage(CoreStage::PostUpdate, send_component_changes::<C>.system())
+            .add_system_to_stage(CoreStage::PostUpdate, send_full_snapshot::<C>.system())
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                apply_component_updates::<C>
+                    .system()
+                    .label("replication_apply")
+                    .after("network_entity_map"),
+            )
+    }
+}
+
+fn encode_update<C: Replicate>(netsync: &NetworkSync, component: &C) -> Option<ServerEvent> {
+    match BincodeCodec::encode(component) {
+        Ok(data) => Some(ServerEvent::ComponentUpdate(*netsync, C::ID, data)),
+        Err(e) => {
+            error!("Failed to encode replicated component {}: {}", C::ID, e);
+            None
+        }
+    }
+}
+
+fn send_component_changes<C: Replicate>(
+    info: Res<GameInfo>,
+    changed: Query<(&NetworkSync, &C), Changed<C>>,
+    mut server_events: EventWriter<ServerEvent>,
+) {
+    if !info.is_network_authority {
+        return;
+    }
+    for (netsync, component) in changed.iter() {
+        if let Some(event) = encode_update(netsync, component) {
+            server_events.send(event);
+        }
+    }
+}
+
+fn send_full_snapshot<C: Replicate>(
+    info: Res<GameInfo>,
+    mut requests: EventReader<FullSnapshotRequest>,
+    all: Query<(&NetworkSync, &C)>,
+    mut net: ResMut<NetworkResource>,
+    tick: Res<GameTick>,
+) {
+    if !info.is_network_authority {
+        return;
+    }
+    for FullSnapshotRequest(handle) in requests.iter() {
+        if let Some(channels) = net.connections.get_mut(handle).and_then(|conn| conn.channels()) {
+            for (netsync, component) in all.iter() {
+                if let Some(event) = encode_update(netsync, component) {
+                    if channels.send::<GameEvent>(GameEvent::ServerUpdate(tick.0, event)).is_some() {
+                        warn!("Snapshot of component {} for handle {} did not fit the channel", C::ID, handle);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn apply_component_updates<C: Replicate>(
+    mut commands: Commands,
+    info: Res<GameInfo>,
+    mut events: EventReader<ServerEvent>,
+    entities: Res<NetworkEntityMap>,
+    mut query: Query<Option<&mut C>>,
+    mut replicated: EventWriter<Replicated>,
+) {
+    if info.is_network_authority {
+        return;
+    }
+    for event in events.iter() {
+        if let ServerEvent::ComponentUpdate(netsync, id, data) = event {
+            if *id != C::ID {
+                continue;
+            }
+            let update = match BincodeCodec::decode::<C>(data) {
+                Ok(update) => update,
+                Err(e) => {
+                    error!("Failed to decode replicated component {}: {}", C::ID, e);
+                    continue;
+                }
+            };
+            let entity = match entities.get(netsync.unique_id) {
+                Some(entity) => entity,
+                None => {
+                    warn!(msg = "Replicated component for unknown entity", netsync = ?netsync);
+                    continue;
+                }
+            };
+            match query.get_mut(entity) {
+                Ok(Some(mut current)) => current.apply(update),
+                Ok(None) => {
+                    commands.entity(entity).insert(update);
+                }
+                Err(_) => continue,
+            }
+            replicated.send(Replicated { entity, component: C::ID });
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, VecDeque};
+use crate::codec::{dequantize, quantize};
+use crate::events::Tick;
+use crate::protocol::NetworkObjectId;
+
+/// How many past snapshots each side keeps around as possible delta baselines
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+/// Locations of all synced entities at a tick. Stored quantized, so unchanged entities compare equal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WorldSnapshot {
+    pub tick: Tick,
+    locations: BTreeMap<NetworkObjectId, (i32, i32)>,
+}
+
+/// A snapshot encoded against a baseline the receiver already has, sent every tick on the snapshot channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotDelta {
+    pub tick: Tick,
+    /// Tick of the acknowledged snapshot this delta was built against, None for a full snapshot
+    pub baseline: Option<Tick>,
+    pub changed: Vec<(NetworkObjectId, i32, i32)>,
+    pub removed: Vec<NetworkObjectId>,
+}
+
+/// Sent back by clients for every snapshot they could reconstruct.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SnapshotAck(pub Tick);
+
+impl WorldSnapshot {
+    pub fn new(tick: Tick) -> Self {
+        WorldSnapshot { tick, locations: BTreeMap::new() }
+    }
+
+    pub fn insert(&mut self, id: NetworkObjectId, location: Vec2) {
+        self.locations.insert(id, (quantize(location.x), quantize(location.y)));
+    }
+
+    pub fn location(&self, id: NetworkObjectId) -> Option<Vec2> {
+        self.locations.get(&id).map(|(x, y)| Vec2::new(dequantize(*x), dequantize(*y)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NetworkObjectId, Vec2)> + '_ {
+        self.locations.iter().map(|(id, (x, y))| (*id, Vec2::new(dequantize(*x), dequantize(*y))))
+    }
+
+    /// Entities whose location differs from `previous`, including ones `previous` did not have.
+    pub fn changed_since<'a>(&'a self, previous: Option<&'a WorldSnapshot>) -> impl Iterator<Item = (NetworkObjectId, Vec2)> + 'a {
+        self.iter().filter(move |(id, _)| {
+            previous.map_or(true, |previous| previous.locations.get(id) != self.locations.get(id))
+        })
+    }
+
+    pub fn delta_from(&self, baseline: Option<&WorldSnapshot>) -> SnapshotDelta {
+        let changed = self.locations.iter()
+            .filter(|(id, location)| baseline.map_or(true, |base| base.locations.get(id) != Some(location)))
+            .map(|(id, (x, y))| (*id, *x, *y))
+            .collect();
+        let removed = baseline
+            .map(|base| base.locations.keys().filter(|id| !self.locations.contains_key(id)).copied().collect())
+            .unwrap_or_default();
+        SnapshotDelta {
+            tick: self.tick,
+            baseline: baseline.map(|base| base.tick),
+            changed,
+            removed,
+        }
+    }
+
+    /// Rebuilds the full snapshot, `baseline` has to be the one the delta names.
+    pub fn from_delta(baseline: Option<&WorldSnapshot>, delta: &SnapshotDelta) -> Self {
+        let mut snapshot = baseline.cloned().unwrap_or_default();
+        snapshot.tick = delta.tick;
+        for id in delta.removed.iter() {
+            snapshot.locations.remove(id);
+        }
+        for (id, x, y) in delta.changed.iter() {
+            snapshot.locations.insert(*id, (*x, *y));
+        }
+        snapshot
+    }
+}
+
+/// The most recent snapshots, oldest first.
+#[derive(Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<WorldSnapshot>,
+}
+
+impl SnapshotHistory {
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        if self.snapshots.len() == SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn get(&self, tick: Tick) -> Option<&WorldSnapshot> {
+        self.snapshots.iter().rev().find(|snapshot| snapshot.tick == tick)
+    }
+
+    pub fn latest(&self) -> Option<&WorldSnapshot> {
+        self.snapshots.back()
+    }
+}
+
+/// Whether tick `a` comes after `b`, survives wrapping.
+pub fn is_newer(a: Tick, b: Tick) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(tick: Tick, locations: &[(NetworkObjectId, f32, f32)]) -> WorldSnapshot {
+        let mut snapshot = WorldSnapshot::new(tick);
+        for (id, x, y) in locations {
+            snapshot.insert(*id, Vec2::new(*x, *y));
+        }
+        snapshot
+    }
+
+    #[test]
+    fn deltas_rebuild_the_snapshot() {
+        let base = snapshot(10, &[(1, 0.0, 0.0), (2, 50.0, 50.0), (3, 100.0, 100.0)]);
+        // 1 stays, 2 moved, 3 despawned and 4 spawned
+        let current = snapshot(12, &[(1, 0.0, 0.0), (2, 55.5, 49.0), (4, 300.0, 200.0)]);
+
+        let delta = current.delta_from(Some(&base));
+        assert_eq!(delta.baseline, Some(10));
+        assert_eq!(delta.changed.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(), vec![2, 4]);
+        assert_eq!(delta.removed, vec![3]);
+        assert_eq!(WorldSnapshot::from_delta(Some(&base), &delta), current);
+    }
+
+    #[test]
+    fn full_snapshots_need_no_baseline() {
+        let current = snapshot(5, &[(1, 1.0, 2.0), (7, 3.0, 4.0)]);
+        let delta = current.delta_from(None);
+        assert_eq!(delta.baseline, None);
+        assert_eq!(delta.changed.len(), 2);
+        assert!(delta.removed.is_empty());
+        assert_eq!(WorldSnapshot::from_delta(None, &delta), current);
+    }
+
+    #[test]
+    fn unchanged_worlds_send_empty_deltas() {
+        let base = snapshot(1, &[(1, 10.0, 10.0)]);
+        let current = snapshot(2, &[(1, 10.0, 10.0)]);
+        let delta = current.delta_from(Some(&base));
+        assert!(delta.changed.is_empty() && delta.removed.is_empty());
+        assert_eq!(WorldSnapshot::from_delta(Some(&base), &delta), current);
+    }
+
+    #[test]
+    fn newer_ticks_survive_wrapping() {
+        assert!(is_newer(11, 10));
+        assert!(!is_newer(10, 11));
+        assert!(!is_newer(10, 10));
+        assert!(is_newer(0, Tick::MAX));
+        assert!(is_newer(5, Tick::MAX - 5));
+        assert!(!is_newer(Tick::MAX, 0));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_snapshots() {
+        let mut history = SnapshotHistory::default();
+        for tick in 0..SNAPSHOT_HISTORY as Tick + 3 {
+            history.push(WorldSnapshot::new(tick));
+        }
+        assert!(history.get(2).is_none());
+        assert!(history.get(3).is_some());
+        assert_eq!(history.latest().map(|latest| latest.tick), Some(SNAPSHOT_HISTORY as Tick + 2));
+    }
+}
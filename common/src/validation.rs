@@ -0,0 +1,157 @@
+use bevy::prelude::*;
+use crate::errors::PlayerCommandValidationError;
+use crate::events::{PlayerCommand, PlayerId};
+use crate::game::PlayerControllable;
+
+/// Clicks are in window coordinates, the world spans a default sized window.
+pub const WORLD_WIDTH: f32 = 1280.0;
+pub const WORLD_HEIGHT: f32 = 720.0;
+
+/// What a rule gets to look at besides the command itself.
+pub struct CommandContext<'a> {
+    pub player_id: PlayerId,
+    /// The unit the command is for
+    pub unit: &'a PlayerControllable,
+}
+
+/// One check a player command has to pass before the server applies it.
+pub trait CommandRule: Send + Sync + 'static {
+    fn check(&mut self, command: &PlayerCommand, context: &CommandContext) -> Result<(), PlayerCommandValidationError>;
+}
+
+/// Runs commands through its rules in order, the first failing rule rejects the command.
+pub struct CommandValidator {
+    rules: Vec<Box<dyn CommandRule>>,
+}
+
+impl CommandValidator {
+    /// A validator without any rules, add them with `with_rule`.
+    pub fn empty() -> Self {
+        CommandValidator { rules: Vec::new() }
+    }
+
+    pub fn with_rule(mut self, rule: impl CommandRule) -> Self {
+        self.add_rule(rule);
+        self
+    }
+
+    pub fn add_rule(&mut self, rule: impl CommandRule) {
+        self.rules.push(Box::new(rule));
+    }
+
+    pub fn validate(&mut self, command: &PlayerCommand, context: &CommandContext) -> Result<(), PlayerCommandValidationError> {
+        self.rules.iter_mut().try_for_each(|rule| rule.check(command, context))
+    }
+}
+
+impl Default for CommandValidator {
+    fn default() -> Self {
+        CommandValidator::empty()
+            .with_rule(Ownership)
+            .with_rule(FiniteCoordinates)
+            .with_rule(WorldBounds { min: Vec2::ZERO, max: Vec2::new(WORLD_WIDTH, WORLD_HEIGHT) })
+    }
+}
+
+/// Players only command their own units.
+pub struct Ownership;
+
+impl CommandRule for Ownership {
+    fn check(&mut self, _: &PlayerCommand, context: &CommandContext) -> Result<(), PlayerCommandValidationError> {
+        if context.player_id != context.unit.owner {
+            Err(PlayerCommandValidationError::NotOwned { attempted: context.player_id, owner: context.unit.owner })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct FiniteCoordinates;
+
+impl CommandRule for FiniteCoordinates {
+    fn check(&mut self, command: &PlayerCommand, _: &CommandContext) -> Result<(), PlayerCommandValidationError> {
+        let PlayerCommand::PointerMoveChange(_, order, _) = command;
+        if order.target.is_finite() {
+            Ok(())
+        } else {
+            Err(PlayerCommandValidationError::NotFinite)
+        }
+    }
+}
+
+/// Targets have to lie within `min` and `max`, inclusive.
+pub struct WorldBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl CommandRule for WorldBounds {
+    fn check(&mut self, command: &PlayerCommand, _: &CommandContext) -> Result<(), PlayerCommandValidationError> {
+        let PlayerCommand::PointerMoveChange(_, order, _) = command;
+        let target = order.target;
+        if target.cmpge(self.min).all() && target.cmple(self.max).all() {
+            Ok(())
+        } else {
+            Err(PlayerCommandValidationError::OutOfBounds { x: target.x, y: target.y })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::MoveOrder;
+    use crate::protocol::NetworkSync;
+
+    const OWNER: PlayerId = 7;
+    const UNIT: PlayerControllable = PlayerControllable { owner: OWNER, last_input: 0 };
+
+    fn move_to(x: f32, y: f32) -> PlayerCommand {
+        PlayerCommand::PointerMoveChange(NetworkSync { unique_id: 1 }, MoveOrder::new(Vec2::new(x, y)), 0)
+    }
+
+    fn context(player_id: PlayerId) -> CommandContext<'static> {
+        CommandContext { player_id, unit: &UNIT }
+    }
+
+    #[test]
+    fn only_owners_command_their_units() {
+        assert_eq!(Ownership.check(&move_to(1.0, 1.0), &context(OWNER)), Ok(()));
+        assert_eq!(
+            Ownership.check(&move_to(1.0, 1.0), &context(8)),
+            Err(PlayerCommandValidationError::NotOwned { attempted: 8, owner: OWNER })
+        );
+    }
+
+    #[test]
+    fn targets_have_to_be_finite() {
+        let context = context(OWNER);
+        assert_eq!(FiniteCoordinates.check(&move_to(1.0, -1.0), &context), Ok(()));
+        for (x, y) in [(f32::NAN, 1.0), (1.0, f32::NAN), (f32::INFINITY, 1.0), (1.0, f32::NEG_INFINITY)] {
+            assert_eq!(FiniteCoordinates.check(&move_to(x, y), &context), Err(PlayerCommandValidationError::NotFinite));
+        }
+    }
+
+    #[test]
+    fn world_bounds_include_their_edges() {
+        let mut bounds = WorldBounds { min: Vec2::ZERO, max: Vec2::new(WORLD_WIDTH, WORLD_HEIGHT) };
+        let context = context(OWNER);
+        for (x, y) in [(0.0, 0.0), (WORLD_WIDTH, WORLD_HEIGHT), (0.0, WORLD_HEIGHT), (WORLD_WIDTH, 0.0)] {
+            assert_eq!(bounds.check(&move_to(x, y), &context), Ok(()), "{},{} is on the edge", x, y);
+        }
+        for (x, y) in [(-0.1, 0.0), (0.0, -0.1), (WORLD_WIDTH + 0.1, 1.0), (1.0, WORLD_HEIGHT + 0.1)] {
+            assert_eq!(bounds.check(&move_to(x, y), &context), Err(PlayerCommandValidationError::OutOfBounds { x, y }));
+        }
+    }
+
+    #[test]
+    fn the_first_failing_rule_rejects() {
+        let mut validator = CommandValidator::default();
+        assert_eq!(validator.validate(&move_to(10.0, 10.0), &context(OWNER)), Ok(()));
+        assert!(matches!(
+            validator.validate(&move_to(f32::NAN, 10.0), &context(8)),
+            Err(PlayerCommandValidationError::NotOwned { .. })
+        ));
+        assert_eq!(validator.validate(&move_to(f32::NAN, 10.0), &context(OWNER)), Err(PlayerCommandValidationError::NotFinite));
+    }
+}
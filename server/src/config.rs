@@ -0,0 +1,269 @@
+use clap::{value_t, App, Arg, ArgMatches};
+use common::auth::TokenClaims;
+use common::bevy::log::{Level, LogSettings};
+use common::protocol::NetworkConfig;
+use crate::rate_limit::RateLimitConfig;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+fn args<'a>() -> App<'a, 'a> {
+    let flag = |name: &'a str, help: &'a str| Arg::with_name(name).long(name).takes_value(true).help(help);
+    App::new("server")
+        .about("Bevy networking proof of concept server")
+        .arg(flag("config", "TOML file with the server config, flags given here take precedence").short("c"))
+        .arg(flag("bind", "Address to listen on"))
+        .arg(flag("port", "Port to listen on").short("p"))
+        .arg(flag("tick-rate", "Server frames per second"))
+        .arg(flag("max-players", "Players allowed at once"))
+        .arg(flag("idle-timeout-ms", "Clients silent for this long get dropped"))
+        .arg(flag("heartbeat-ms", "Heartbeat interval of quiet connections"))
+        .arg(flag("log-level", "One of trace, debug, info, warn, error"))
+        .arg(flag("issue-token", "Prints a token for player ID:NAME signed with the auth secret, then exits").value_name("ID:NAME"))
+        .arg(flag("token-ttl", "Seconds until tokens printed by --issue-token expire, they never do by default").value_name("SECONDS"))
+}
+
+/// Parses a flag if it was given, exits with clap's usual message if it does not parse.
+fn value<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.is_present(name).then(|| value_t!(matches, name, T).unwrap_or_else(|e| e.exit()))
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error
+    },
+    #[error("Invalid config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error
+    },
+    #[error("Unknown log level {0:?}")]
+    LogLevel(String),
+    #[error("Expected a token for ID:NAME, got {0:?}")]
+    TokenClaims(String),
+    #[error("Issuing tokens needs an auth_secret in the config file")]
+    NoAuthSecret,
+    #[error("tick_rate has to be at least 1")]
+    TickRate,
+    #[error("heartbeat_ms ({heartbeat_ms}) has to stay below idle_timeout_ms ({idle_timeout_ms})")]
+    Heartbeat {
+        heartbeat_ms: usize,
+        idle_timeout_ms: usize
+    }
+}
+
+/// Everything the server can be configured with, inserted as a resource before the app starts.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: IpAddr,
+    pub port: u16,
+    /// Server frames per second, the simulation itself always steps at `TICKS_PER_SECOND`
+    pub tick_rate: u32,
+    /// Clients saying hello while this many players are connected get turned away
+    pub max_players: usize,
+    pub log_level: String,
+    pub network: NetworkConfig,
+    pub rate_limit: RateLimitConfig,
+    /// Clients have to present a token signed with this secret, see `common::auth`
+    pub auth_secret: Option<String>,
+    /// Seed of the network and player ids, random when not set. Fixed seeds give the same ids every run
+    pub id_seed: Option<u32>,
+    /// How long the pointers of a player whose connection dropped wait for them to come back, in seconds
+    pub session_grace: f64,
+    /// Set by `--issue-token`, the server prints a token for these claims and exits instead of running
+    #[serde(skip)]
+    pub issue_token: Option<TokenClaims>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: common::SERVER_PORT,
+            tick_rate: 60,
+            max_players: 16,
+            log_level: "info".to_string(),
+            network: NetworkConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            auth_secret: None,
+            id_seed: None,
+            session_grace: 30.0,
+            issue_token: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the command line, and the config file if one was passed.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_args(&args().get_matches())
+    }
+
+    fn from_args(args: &ArgMatches) -> Result<Self, ConfigError> {
+        let mut config = match value::<PathBuf>(args, "config") {
+            Some(path) => {
+                let text = std::fs::read_to_string(&path)
+                    .map_err(|source| ConfigError::Read { path: path.clone(), source })?;
+                toml::from_str(&text).map_err(|source| ConfigError::Parse { path: path.clone(), source })?
+            }
+            None => ServerConfig::default(),
+        };
+
+        if let Some(bind) = value(args, "bind") {
+            config.bind = bind;
+        }
+        if let Some(port) = value(args, "port") {
+            config.port = port;
+        }
+        if let Some(tick_rate) = value(args, "tick-rate") {
+            config.tick_rate = tick_rate;
+        }
+        if let Some(max_players) = value(args, "max-players") {
+            config.max_players = max_players;
+        }
+        if let Some(idle_timeout_ms) = value(args, "idle-timeout-ms") {
+            config.network.idle_timeout_ms = idle_timeout_ms;
+        }
+        if let Some(heartbeat_ms) = value(args, "heartbeat-ms") {
+            config.network.heartbeat_ms = heartbeat_ms;
+        }
+        if let Some(log_level) = value(args, "log-level") {
+            config.log_level = log_level;
+        }
+        if let Some(claims) = value::<String>(args, "issue-token") {
+            if config.auth_secret.is_none() {
+                return Err(ConfigError::NoAuthSecret);
+            }
+            let (player_id, name) = claims
+                .split_once(':')
+                .and_then(|(id, name)| Some((id.parse().ok().filter(|id| *id != 0)?, name.to_string())))
+                .ok_or(ConfigError::TokenClaims(claims))?;
+            let expires_at = value::<u64>(args, "token-ttl").map(|ttl| {
+                SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()) + ttl
+            });
+            config.issue_token = Some(TokenClaims { player_id, name, expires_at });
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings the server cannot run with, whether they came from the file or the flags.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.tick_rate == 0 {
+            return Err(ConfigError::TickRate);
+        }
+        let NetworkConfig { heartbeat_ms, idle_timeout_ms, .. } = self.network;
+        if heartbeat_ms >= idle_timeout_ms {
+            return Err(ConfigError::Heartbeat { heartbeat_ms, idle_timeout_ms });
+        }
+        Ok(())
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn log_settings(&self) -> Result<LogSettings, ConfigError> {
+        let level = self
+            .log_level
+            .parse::<Level>()
+            .map_err(|_| ConfigError::LogLevel(self.log_level.clone()))?;
+        Ok(LogSettings { level, ..Default::default() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_flags(flags: &[&str]) -> Result<ServerConfig, ConfigError> {
+        let matches = args().get_matches_from(std::iter::once("server").chain(flags.iter().copied()));
+        ServerConfig::from_args(&matches)
+    }
+
+    /// Writes `text` to a config file only this test uses.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("server-config-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn toml_keys_override_defaults() {
+        let config: ServerConfig = toml::from_str(
+            "port = 1234\ntick_rate = 30\n[network]\nheartbeat_ms = 500\n[rate_limit]\ncommand_burst = 5.0\n",
+        )
+        .unwrap();
+        assert_eq!(config.port, 1234);
+        assert_eq!(config.tick_rate, 30);
+        assert_eq!(config.network.heartbeat_ms, 500);
+        assert_eq!(config.network.idle_timeout_ms, NetworkConfig::default().idle_timeout_ms);
+        assert_eq!(config.rate_limit.command_burst, 5.0);
+        assert_eq!(config.max_players, ServerConfig::default().max_players);
+    }
+
+    #[test]
+    fn unknown_toml_keys_are_rejected() {
+        assert!(toml::from_str::<ServerConfig>("prot = 1234").is_err());
+        assert!(toml::from_str::<ServerConfig>("[network]\nheartbeat = 5").is_err());
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let path = config_file("precedence", "port = 1234\ntick_rate = 30\nmax_players = 4\n");
+        let config = from_flags(&["--config", path.to_str().unwrap(), "--port", "4321", "--tick-rate", "20"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.port, 4321);
+        assert_eq!(config.tick_rate, 20);
+        assert_eq!(config.max_players, 4);
+    }
+
+    #[test]
+    fn invalid_files_are_reported() {
+        let path = config_file("invalid", "port = \"not a port\"\n");
+        let result = from_flags(&["--config", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::Parse { .. })));
+        assert!(matches!(from_flags(&["--config", "/nonexistent/server.toml"]), Err(ConfigError::Read { .. })));
+    }
+
+    #[test]
+    fn issued_tokens_expire_after_their_ttl() {
+        let path = config_file("token-ttl", "auth_secret = \"secret\"\n");
+        let forever = from_flags(&["--config", path.to_str().unwrap(), "--issue-token", "42:alice"]).unwrap();
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let expiring =
+            from_flags(&["--config", path.to_str().unwrap(), "--issue-token", "42:alice", "--token-ttl", "3600"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(forever.issue_token.unwrap().expires_at, None);
+        let expires_at = expiring.issue_token.unwrap().expires_at.unwrap();
+        assert!((before + 3600..=before + 3601).contains(&expires_at));
+    }
+
+    #[test]
+    fn zero_tick_rate_is_rejected() {
+        assert!(matches!(from_flags(&["--tick-rate", "0"]), Err(ConfigError::TickRate)));
+        let path = config_file("tick-rate", "tick_rate = 0\n");
+        let result = from_flags(&["--config", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::TickRate)));
+    }
+
+    #[test]
+    fn heartbeat_has_to_stay_below_the_idle_timeout() {
+        assert!(from_flags(&["--heartbeat-ms", "999", "--idle-timeout-ms", "1000"]).is_ok());
+        assert!(matches!(
+            from_flags(&["--heartbeat-ms", "1000", "--idle-timeout-ms", "1000"]),
+            Err(ConfigError::Heartbeat { heartbeat_ms: 1000, idle_timeout_ms: 1000 })
+        ));
+        assert!(matches!(from_flags(&["--idle-timeout-ms", "100"]), Err(ConfigError::Heartbeat { .. })));
+    }
+}
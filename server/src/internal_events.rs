@@ -1,15 +1,20 @@
 use common::bevy::app::{AppBuilder, Plugin};
 use common::bevy::math::Vec2;
-use common::bevy::prelude::{IntoSystem, ResMut};
+use common::bevy::prelude::{info, IntoSystem, ParallelSystemDescriptorCoercion, Res, ResMut};
 use common::bevy_networking_turbulence::NetworkResource;
-use common::events::{GameEvent, ServerEvent};
+use common::events::{GameEvent, PlayerId, ServerEvent};
 use common::events::ServerEvent::PointerSpawn;
-use common::game::{Location, Movable, PlayerControllable};
+use common::game::{GameTick, Location, PlayerControllable};
+use common::replication::FullSnapshotRequest;
 use common::protocol::{ClientIdentification, MetaInformation, NetworkSync};
+use common::ids::IdAllocator;
 use crate::{broadcast_server_event, ConnectionHandle, EventReader, EventWriter, Query, Transform};
 
 pub enum Internal {
-    PlayerConnected(ConnectionHandle, ClientIdentification)
+    PlayerConnected(ConnectionHandle, ClientIdentification),
+    /// A reconnecting client took its session back, its pointer is still around
+    PlayerResumed(ConnectionHandle, ClientIdentification),
+    PlayerDisconnected(ConnectionHandle, PlayerId)
 }
 
 pub struct InternalPlugin {}
@@ -17,9 +22,12 @@ pub struct InternalPlugin {}
 impl Plugin for InternalPlugin {
     fn build(&self, app: &mut AppBuilder) {
         app.add_event::<Internal>();
-        app.add_system(handle_new_player_connections.system())
-            .add_system(spawn_point_on_player_connect.system())
-            .add_system(sync_pointers_on_connect.system());
+        // a pointer is broadcast and becomes an entity in the frame it spawns, players in by then get the broadcast
+        // and later ones find it in the query
+        app.add_system(handle_new_player_connections.system().after("client_meta"))
+            .add_system(spawn_point_on_player_connect.system().label("player_spawns").after("client_meta").before("pointer_spawns"))
+            .add_system(sync_pointers_on_connect.system().after("client_meta"))
+            .add_system(despawn_pointers_on_disconnect.system());
     }
 }
 
@@ -28,7 +36,7 @@ fn handle_new_player_connections(
     mut net: ResMut<NetworkResource>)
 {
     for event in reader.iter() {
-        if let Internal::PlayerConnected(handle, id) = event {
+        if let Internal::PlayerConnected(handle, id) | Internal::PlayerResumed(handle, id) = event {
             let to_send = MetaInformation::ClientIdentificationMessage(id.clone());
             net.connections.get_mut(&handle).unwrap().channels().unwrap().send::<MetaInformation>(to_send);
         }
@@ -37,12 +45,13 @@ fn handle_new_player_connections(
 
 fn spawn_point_on_player_connect(
     mut reader: EventReader<Internal>,
-    mut server_events: EventWriter<ServerEvent>
+    mut server_events: EventWriter<ServerEvent>,
+    mut ids: ResMut<IdAllocator>
 ) {
     for event in reader.iter() {
         if let Internal::PlayerConnected(_, id) = event {
             broadcast_server_event(&mut server_events, PointerSpawn(
-                NetworkSync::new(),
+                ids.network_sync(),
                 id.player_id.clone(),
                 Vec2::new(50.0, 50.0)
             ));
@@ -52,29 +61,40 @@ fn spawn_point_on_player_connect(
 
 fn sync_pointers_on_connect(
     mut reader: EventReader<Internal>,
-    pointers: Query<(&NetworkSync, &Movable, &Location, &PlayerControllable)>,
-    mut net: ResMut<NetworkResource>
+    pointers: Query<(&NetworkSync, &Location, &PlayerControllable)>,
+    mut net: ResMut<NetworkResource>,
+    tick: Res<GameTick>,
+    mut snapshot_requests: EventWriter<FullSnapshotRequest>
 ) {
     for event in reader.iter() {
-        if let Internal::PlayerConnected(handle, _id) = event {
-            for (nsync, movable, location, player) in pointers.iter() {
-                net.connections.get_mut(&handle).unwrap().channels().unwrap().send::<GameEvent>(GameEvent::ServerUpdate(ServerEvent::PointerSpawn(
+        if let Internal::PlayerConnected(handle, _id) | Internal::PlayerResumed(handle, _id) = event {
+            for (nsync, location, player) in pointers.iter() {
+                net.connections.get_mut(&handle).unwrap().channels().unwrap().send::<GameEvent>(GameEvent::ServerUpdate(tick.0, ServerEvent::PointerSpawn(
                     nsync.clone(), player.owner, **location
                 )));
-                net.connections.get_mut(&handle).unwrap().channels().unwrap().send::<GameEvent>(GameEvent::ServerUpdate(ServerEvent::EntityMovementChange(
-                    nsync.clone(), *movable, **location
-                )));
             }
+            // replicated components follow the spawns
+            snapshot_requests.send(FullSnapshotRequest(*handle));
         }
     }
 }
 
-
-
-
-
-
-
-
-
-
+fn despawn_pointers_on_disconnect(
+    mut reader: EventReader<Internal>,
+    pointers: Query<(&NetworkSync, &PlayerControllable)>,
+    mut server_events: EventWriter<ServerEvent>,
+    mut ids: ResMut<IdAllocator>
+) {
+    for event in reader.iter() {
+        if let Internal::PlayerDisconnected(handle, player_id) = event {
+            info!("Removing pointers of player {} on handle {}", player_id, handle);
+            for (nsync, player) in pointers.iter() {
+                if player.owner == *player_id {
+                    broadcast_server_event(&mut server_events, ServerEvent::EntityDespawn(*nsync));
+                    ids.release_object(nsync.unique_id);
+                }
+            }
+            broadcast_server_event(&mut server_events, ServerEvent::PlayerLeft(*player_id));
+        }
+    }
+}
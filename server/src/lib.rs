@@ -0,0 +1,663 @@
+pub mod config;
+mod internal_events;
+mod rate_limit;
+mod sessions;
+
+pub use crate::config::{ConfigError, ServerConfig};
+use crate::internal_events::{Internal, InternalPlugin};
+use crate::rate_limit::{report_rate_limits, MessageKind, RateLimits, Verdict};
+use crate::sessions::Sessions;
+use common::bevy::ecs::system::System;
+use common::bevy::prelude::*;
+use common::bevy::utils::HashMap;
+use common::bevy_networking_turbulence::{ConnectionHandle, NetworkError, NetworkEvent, NetworkResource};
+use common::events::*;
+use common::game::{GameInfo, GameTick, Movable, PlayerControllable, Location, SimulationStage, TICKS_PER_SECOND};
+use common::get_random;
+use common::ids::IdAllocator;
+use common::validation::{CommandContext, CommandRule, CommandValidator};
+use common::protocol::{ClientIdentification, ConnectionPlugin, Hello, MetaInformation, NetworkEntityMap, NetworkSync};
+use common::snapshot::{is_newer, SnapshotAck, SnapshotDelta, SnapshotHistory, WorldSnapshot};
+use common::clock::{ClockSync, Pong, PING_INTERVAL};
+use common::auth::{self, TokenClaims};
+use common::errors::{AuthError, DisconnectReason, PlayerCommandValidationError};
+use std::cmp::Reverse;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type ClientHandleMap = HashMap<ConnectionHandle, PlayerId>;
+type ClientClocks = HashMap<ConnectionHandle, ClockSync>;
+/// Newest world snapshot each client confirmed, deltas for it are built against that one.
+/// A newtype, as a bare `HashMap<ConnectionHandle, Tick>` would be the same resource as `ClientHandleMap`
+#[derive(Default)]
+struct ClientSnapshotAcks(HashMap<ConnectionHandle, Tick>);
+/// Kicked clients and when their connection gets dropped
+type KickedClients = HashMap<ConnectionHandle, f64>;
+/// A command, the tick it was stamped with and the connection of the player who sent it, so rejections can be answered
+type AssociatedCommand = (ConnectionHandle, PlayerId, Tick, PlayerCommand);
+
+/// How long a kicked client keeps its connection, so its disconnect reason has time to arrive, in seconds
+const KICK_LINGER: f64 = 0.5;
+/// How many ticks ahead a command may be stamped, later ones are applied at this limit
+const MAX_COMMAND_LEAD: Tick = TICKS_PER_SECOND as Tick;
+
+/// Validated commands waiting for the tick they are applied at.
+#[derive(Default)]
+struct CommandBuffer(Vec<(Tick, AcceptedCommand)>);
+
+impl CommandBuffer {
+    /// Commands stamped with a tick that already passed apply at the current one.
+    fn push(&mut self, stamped: Tick, current: Tick, command: AcceptedCommand) {
+        let apply_at = if is_newer(current, stamped) {
+            current
+        } else if stamped.wrapping_sub(current) > MAX_COMMAND_LEAD {
+            current.wrapping_add(MAX_COMMAND_LEAD)
+        } else {
+            stamped
+        };
+        self.0.push((apply_at, command));
+    }
+
+    /// Takes out the commands due at `tick`, in the order of their ticks and then of their arrival.
+    fn take_due(&mut self, tick: Tick) -> Vec<AcceptedCommand> {
+        let (mut due, waiting): (Vec<_>, Vec<_>) = self.0.drain(..).partition(|(at, _)| !is_newer(*at, tick));
+        self.0 = waiting;
+        due.sort_by_key(|(at, _)| Reverse(tick.wrapping_sub(*at)));
+        due.into_iter().map(|(_, command)| command).collect()
+    }
+}
+
+/// Send to close a connection, the client is told why before it gets dropped.
+pub struct Kick(pub ConnectionHandle, pub DisconnectReason);
+
+/// A client is gone, either its connection dropped or it was kicked.
+struct ConnectionClosed {
+    handle: ConnectionHandle,
+    /// Whether the client may come back and resume its session
+    resumable: bool,
+}
+
+/// Raised for every player command the server validated, in the simulation tick that applies it,
+/// before units move. Game systems see it in that same tick.
+pub struct AcceptedCommand {
+    pub handle: ConnectionHandle,
+    pub player_id: PlayerId,
+    pub command: PlayerCommand,
+}
+
+/// The whole server, for an app that already has `MinimalPlugins`. Starts listening on `config.address()`,
+/// games extend it through `ServerAppExt`.
+pub struct ServerPlugin {
+    pub config: ServerConfig,
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut AppBuilder) {
+        add_server(app, self.config.clone());
+    }
+}
+
+/// Hooks for games running on top of `ServerPlugin`, add the plugin first.
+pub trait ServerAppExt {
+    /// Runs `system` every simulation tick, after units moved and before the tick's snapshot goes out.
+    fn add_game_system<S: System<In = (), Out = ()>>(&mut self, system: S) -> &mut Self;
+    /// Player commands have to pass `rule` as well, after the built in rules.
+    fn add_command_rule(&mut self, rule: impl CommandRule) -> &mut Self;
+}
+
+impl ServerAppExt for AppBuilder {
+    fn add_game_system<S: System<In = (), Out = ()>>(&mut self, system: S) -> &mut Self {
+        self.add_system_to_stage(SimulationStage, system.after("move_movable").before("advance_tick"))
+    }
+
+    fn add_command_rule(&mut self, rule: impl CommandRule) -> &mut Self {
+        self.world_mut()
+            .get_resource_mut::<CommandValidator>()
+            .expect("ServerPlugin has to be added before command rules")
+            .add_rule(rule);
+        self
+    }
+}
+
+fn add_server(app: &mut AppBuilder, config: ServerConfig) {
+    app.add_plugin(ConnectionPlugin { config: config.network.clone() })
+        .add_plugin(common::game::GameEnginePlugin { settings: GameInfo { is_network_authority: true, headless: true } })
+        .add_plugin(InternalPlugin {});
+
+    let id_seed = config.id_seed.unwrap_or_else(get_random);
+    app.insert_resource(config)
+        .insert_resource(ClientHandleMap::default())
+        .insert_resource(ClientClocks::default())
+        .insert_resource(ClientSnapshotAcks::default())
+        .insert_resource(KickedClients::default())
+        .insert_resource(Sessions::default())
+        .insert_resource(RateLimits::default())
+        .insert_resource(SnapshotHistory::default())
+        .insert_resource(IdAllocator::seeded(id_seed))
+        .insert_resource(CommandValidator::default())
+        .insert_resource(CommandBuffer::default());
+
+    app.add_event::<AssociatedCommand>()
+        .add_event::<AcceptedCommand>()
+        .add_event::<Kick>()
+        .add_event::<ConnectionClosed>();
+
+    app.add_startup_system(startup.system());
+
+    app.add_system(handle_clients_commands.system())
+        .add_system(handle_clients_meta.system().label("client_meta"))
+        .add_system(kick_clients.system())
+        .add_system(drop_kicked_clients.system())
+        .add_system(forget_closed_connections.system())
+        .add_system(expire_sessions.system())
+        .add_system(report_rate_limits.system())
+        .add_system(ping_clients.system())
+        .add_system(receive_snapshot_acks.system())
+        .add_system(handle_client_connections.system())
+        .add_system(handle_client_move_commands.system())
+        .add_system(broadcast_server_events.system().after("player_spawns"))
+        .add_system_to_stage(SimulationStage, apply_due_commands.system().before("move_movable"))
+        .add_system_to_stage(SimulationStage, send_world_snapshots.system().after("advance_tick"));
+}
+
+
+fn startup(mut net: ResMut<NetworkResource>, game_info: ResMut<GameInfo>, config: Res<ServerConfig>) {
+    common::protocol::network_setup(&mut net);
+
+    let server_address = config.address();
+    info!("Server listening on {}, at most {} players", server_address, config.max_players);
+
+    if game_info.headless {
+        info!("Server is headless");
+    } else {
+        info!("Server is NOT headless!");
+    }
+
+    net.listen(server_address, None, None);
+}
+
+fn handle_clients_commands(
+    mut net: ResMut<NetworkResource>,
+    mut player_command_queue: EventWriter<AssociatedCommand>,
+    client_player_map: Res<ClientHandleMap>,
+    mut limits: ResMut<RateLimits>,
+    mut kicks: EventWriter<Kick>,
+    config: Res<ServerConfig>,
+    time: Res<Time>,
+) {
+    // info!("Handling clients...");
+    let now = time.seconds_since_startup();
+    for (handle, connection) in net.connections.iter_mut() {
+        let channels = connection.channels().unwrap();
+        while let Some(game_event) = channels.recv::<GameEvent>() {
+            if !allow(&mut limits, &mut kicks, *handle, MessageKind::Command, &config, now) {
+                // the player predicted the command already and has to roll it back
+                if let GameEvent::PlayerCommand(_, PlayerCommand::PointerMoveChange(_, _, sequence)) = game_event {
+                    let reason = PlayerCommandValidationError::RateExceeded { per_second: config.rate_limit.commands_per_second };
+                    if channels.send(GameEvent::CommandRejected(sequence, reason)).is_some() {
+                        warn!("Rejection of command {} did not fit the channel to {}", sequence, handle);
+                    }
+                }
+                continue;
+            }
+            match game_event {
+                GameEvent::PlayerCommand(tick, cmd) => {
+                    if let Some(id) = client_player_map.get(handle) {
+                        player_command_queue.send((*handle, *id, tick, cmd));
+                    } else {
+                        warn!("An unmapped client {} sent command", handle);
+                    }
+                }
+                GameEvent::ServerUpdate(..) | GameEvent::CommandRejected(..) => {
+                    error!("Client should never send a GameEvent!")
+                }
+            }
+
+            /*match network_event {
+                NetworkEvent::Packet(handle, packet) => {
+                    //let content: GameEvent = packet.into();
+                    let content = common::serde_form::from_slice::<GameEvent>(packet.as_bytes()).expect("Failed to deserialize packet");
+                }
+
+                NetworkEvent::Connected(handle) => {
+                    info!("New client connected {}", handle)
+                }
+                NetworkEvent::Disconnected(handle) => {}
+                NetworkEvent::Error(handle, error) => {
+                    let err_message = match error {
+                        NetworkError::TurbulenceChannelError(e) => { e.to_string() }
+                        NetworkError::IoError(e) => { e.to_string() }
+                        NetworkError::MissedHeartbeat => { "Missed heartbeat".to_string() }
+                        NetworkError::Disconnected => { "Errorneous disconnect".to_string() }
+                    };
+                    error!("Error network event from handle {}: {}", handle, err_message)
+                }
+            }
+
+             */
+        }
+    }
+}
+
+fn handle_clients_meta(
+    mut net: ResMut<NetworkResource>,
+    mut clocks: ResMut<ClientClocks>,
+    mut handle_map: ResMut<ClientHandleMap>,
+    kicked: Res<KickedClients>,
+    mut sessions: ResMut<Sessions>,
+    mut ids: ResMut<IdAllocator>,
+    mut internal_events: EventWriter<Internal>,
+    mut kicks: EventWriter<Kick>,
+    mut limits: ResMut<RateLimits>,
+    config: Res<ServerConfig>,
+    time: Res<Time>,
+    tick: Res<GameTick>,
+) {
+    let now = time.seconds_since_startup();
+    for (handle, connection) in net.connections.iter_mut() {
+        let channels = connection.channels().unwrap();
+        while let Some(meta) = channels.recv::<MetaInformation>() {
+            if !allow(&mut limits, &mut kicks, *handle, MessageKind::Meta, &config, now) {
+                continue;
+            }
+            match meta {
+                MetaInformation::Hello(hello) => {
+                    if handle_map.contains_key(handle) || kicked.contains_key(handle) {
+                        warn!("Client {} said hello twice", handle);
+                        continue;
+                    }
+                    let claims = match hello.check().and_then(|_| authenticate(&hello, &config)) {
+                        Ok(claims) => claims,
+                        Err(reason) => {
+                            kicks.send(Kick(*handle, reason));
+                            continue;
+                        }
+                    };
+                    // authenticated players always get their own session back
+                    let session = match &claims {
+                        Some(claims) => sessions.find(claims.player_id),
+                        None => hello.session,
+                    };
+                    if let Some((token, (player_id, name, previous))) =
+                        session.and_then(|token| sessions.resume(token, *handle).map(|resumed| (token, resumed)))
+                    {
+                        info!("Client {} resumed the session of player {} ({})", handle, player_id, name);
+                        if let Some(previous) = previous {
+                            handle_map.remove(&previous);
+                            kicks.send(Kick(previous, DisconnectReason::Kicked("Session resumed from another connection".to_string())));
+                        }
+                        handle_map.insert(*handle, player_id);
+                        internal_events.send(Internal::PlayerResumed(*handle, ClientIdentification::new(player_id, token, name)));
+                    } else if handle_map.len() >= config.max_players {
+                        kicks.send(Kick(*handle, DisconnectReason::ServerFull));
+                    } else {
+                        let (player_id, name) = match claims {
+                            Some(claims) => (claims.player_id, claims.name),
+                            None => {
+                                let player_id = ids.player_id();
+                                (player_id, format!("Player {}", player_id))
+                            }
+                        };
+                        let token = sessions.open(player_id, name.clone(), *handle);
+                        info!("Client {} is player {} ({})", handle, player_id, name);
+                        handle_map.insert(*handle, player_id);
+                        internal_events.send(Internal::PlayerConnected(*handle, ClientIdentification::new(player_id, token, name)));
+                    }
+                }
+                MetaInformation::Ping(ping) => {
+                    channels.send::<MetaInformation>(MetaInformation::Pong(Pong::answer(&ping, now, tick.0)));
+                }
+                MetaInformation::Pong(pong) => {
+                    if let Some(clock) = clocks.get_mut(handle) {
+                        clock.record(&pong, now);
+                    }
+                }
+                MetaInformation::Heartbeat => {}
+                other => {
+                    warn!("Client {} sent unexpected meta information {:?}", handle, other);
+                }
+            }
+        }
+    }
+}
+
+/// Claims of the token the client said hello with, None if the server does not ask for tokens.
+fn authenticate(hello: &Hello, config: &ServerConfig) -> Result<Option<TokenClaims>, DisconnectReason> {
+    let secret = match &config.auth_secret {
+        Some(secret) => secret,
+        None => return Ok(None),
+    };
+    let token = hello.token.as_deref().ok_or(DisconnectReason::Unauthenticated(AuthError::Missing))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    auth::verify(token, secret.as_bytes(), now)
+        .map(Some)
+        .map_err(DisconnectReason::Unauthenticated)
+}
+
+/// Whether a message is within the client's budget, kicks clients that keep flooding.
+fn allow(
+    limits: &mut RateLimits,
+    kicks: &mut EventWriter<Kick>,
+    handle: ConnectionHandle,
+    kind: MessageKind,
+    config: &ServerConfig,
+    now: f64,
+) -> bool {
+    match limits.check(handle, kind, &config.rate_limit, now) {
+        Verdict::Allow => true,
+        Verdict::Drop => false,
+        Verdict::Kick => {
+            kicks.send(Kick(handle, DisconnectReason::Flooding));
+            false
+        }
+    }
+}
+
+/// Tells kicked clients why, their player is removed right away and the connection a bit later.
+fn kick_clients(
+    mut kicks: EventReader<Kick>,
+    mut net: ResMut<NetworkResource>,
+    mut kicked: ResMut<KickedClients>,
+    mut closed: EventWriter<ConnectionClosed>,
+    time: Res<Time>,
+) {
+    for Kick(handle, reason) in kicks.iter() {
+        if kicked.contains_key(handle) {
+            continue;
+        }
+        let channels = match net.connections.get_mut(handle).and_then(|conn| conn.channels()) {
+            Some(channels) => channels,
+            None => continue,
+        };
+        warn!("Kicking client {}: {}", handle, reason);
+        channels.send::<MetaInformation>(MetaInformation::DisconnectReason(reason.clone()));
+        channels.flush::<MetaInformation>();
+        kicked.insert(*handle, time.seconds_since_startup() + KICK_LINGER);
+        closed.send(ConnectionClosed { handle: *handle, resumable: false });
+    }
+}
+
+fn drop_kicked_clients(
+    mut net: ResMut<NetworkResource>,
+    mut kicked: ResMut<KickedClients>,
+    time: Res<Time>,
+) {
+    let now = time.seconds_since_startup();
+    let expired: Vec<ConnectionHandle> = kicked
+        .iter()
+        .filter(|(_, deadline)| now >= **deadline)
+        .map(|(handle, _)| *handle)
+        .collect();
+    for handle in expired {
+        kicked.remove(&handle);
+        // dropping the connection ourselves raises no Disconnected event
+        net.disconnect(handle);
+    }
+}
+
+fn forget_closed_connections(
+    mut closed: EventReader<ConnectionClosed>,
+    mut internal_events: EventWriter<Internal>,
+    mut handle_map: ResMut<ClientHandleMap>,
+    mut clocks: ResMut<ClientClocks>,
+    mut acks: ResMut<ClientSnapshotAcks>,
+    mut sessions: ResMut<Sessions>,
+    mut ids: ResMut<IdAllocator>,
+    mut limits: ResMut<RateLimits>,
+    config: Res<ServerConfig>,
+    time: Res<Time>,
+) {
+    for ConnectionClosed { handle, resumable } in closed.iter() {
+        clocks.remove(handle);
+        limits.forget(*handle);
+        acks.0.remove(handle);
+        if let Some(player_id) = handle_map.remove(handle) {
+            if *resumable {
+                info!("Keeping player {} around for {} seconds", player_id, config.session_grace);
+                sessions.detach(*handle, time.seconds_since_startup(), config.session_grace);
+            } else {
+                sessions.end(*handle);
+                ids.release_player(player_id);
+                internal_events.send(Internal::PlayerDisconnected(*handle, player_id));
+            }
+        }
+    }
+}
+
+fn expire_sessions(
+    mut sessions: ResMut<Sessions>,
+    mut internal_events: EventWriter<Internal>,
+    mut ids: ResMut<IdAllocator>,
+    time: Res<Time>,
+) {
+    for (handle, player_id) in sessions.expire(time.seconds_since_startup()) {
+        info!("Player {} did not come back", player_id);
+        ids.release_player(player_id);
+        internal_events.send(Internal::PlayerDisconnected(handle, player_id));
+    }
+}
+
+fn ping_clients(
+    mut net: ResMut<NetworkResource>,
+    mut clocks: ResMut<ClientClocks>,
+    handle_map: Res<ClientHandleMap>,
+    time: Res<Time>,
+    mut last_ping: Local<f64>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *last_ping < PING_INTERVAL {
+        return;
+    }
+    *last_ping = now;
+    for (handle, connection) in net.connections.iter_mut().filter(|(handle, _)| handle_map.contains_key(handle)) {
+        let ping = clocks.entry(*handle).or_default().next_ping(now);
+        connection.channels().unwrap().send::<MetaInformation>(MetaInformation::Ping(ping));
+    }
+}
+
+fn broadcast_server_events(
+    mut server_events: EventReader<ServerEvent>,
+    mut net: ResMut<NetworkResource>,
+    handle_map: Res<ClientHandleMap>,
+    tick: Res<GameTick>,
+) {
+    server_events.iter().for_each(|event| {
+        info!(broadcasting = ?event);
+        net.connections
+            .iter_mut()
+            .filter(|(handle, _)| handle_map.contains_key(handle))
+            .for_each(|(_, conn)| {
+                conn.channels()
+                    .unwrap()
+                    .send::<GameEvent>(GameEvent::ServerUpdate(tick.0, event.clone()));
+            });
+    });
+}
+
+fn receive_snapshot_acks(mut net: ResMut<NetworkResource>, mut acks: ResMut<ClientSnapshotAcks>) {
+    for (handle, connection) in net.connections.iter_mut() {
+        let channels = connection.channels().unwrap();
+        while let Some(SnapshotAck(tick)) = channels.recv::<SnapshotAck>() {
+            // acks are unreliable too, an old one must not move the baseline back
+            let newest = acks.0.entry(*handle).or_insert(tick);
+            if is_newer(tick, *newest) {
+                *newest = tick;
+            }
+        }
+    }
+}
+
+/// Every tick each client gets the world as a delta against the last snapshot it acknowledged,
+/// or in full if that snapshot is too old to still be in the history.
+fn send_world_snapshots(
+    synced: Query<(&NetworkSync, &Location)>,
+    mut net: ResMut<NetworkResource>,
+    mut history: ResMut<SnapshotHistory>,
+    acks: Res<ClientSnapshotAcks>,
+    handle_map: Res<ClientHandleMap>,
+    tick: Res<GameTick>,
+) {
+    let mut snapshot = WorldSnapshot::new(tick.0);
+    for (netsync, location) in synced.iter() {
+        snapshot.insert(netsync.unique_id, **location);
+    }
+
+    // clients that did not finish the handshake do not know about any entity yet
+    for (handle, connection) in net.connections.iter_mut().filter(|(handle, _)| handle_map.contains_key(handle)) {
+        let baseline = acks.0.get(handle).and_then(|acked| history.get(*acked));
+        let delta = snapshot.delta_from(baseline);
+        let channels = connection.channels().unwrap();
+        if channels.send::<SnapshotDelta>(delta).is_some() {
+            warn!("Snapshot {} for handle {} did not fit the channel", tick.0, handle);
+        }
+        // unreliable channels only send out on flush
+        channels.flush::<SnapshotDelta>();
+    }
+
+    history.push(snapshot);
+}
+
+fn handle_client_connections(
+    mut reader: EventReader<NetworkEvent>,
+    mut closed: EventWriter<ConnectionClosed>,
+    mut kicked: ResMut<KickedClients>,
+) {
+    for event in reader.iter() {
+        match event {
+            NetworkEvent::Connected(handle) => {
+                // the player id is handed out once the client's hello checks out
+                info!("New client! Handle is {}, waiting for its hello", handle);
+            }
+            NetworkEvent::Disconnected(handle) => {
+                info!("Client {} disconnected.", handle);
+                kicked.remove(handle);
+                closed.send(ConnectionClosed { handle: *handle, resumable: true });
+            }
+            NetworkEvent::Packet(_, packet) => {
+                info!(packet_received = ?packet);
+            }
+            NetworkEvent::Error(handle, NetworkError::MissedHeartbeat) => {
+                // a Disconnected follows, the session stays resumable
+                warn!("Client {} disconnected: {}", handle, DisconnectReason::Timeout);
+            }
+            NetworkEvent::Error(handle, error) => {
+                info!(handle = handle, error = ?error);
+            }
+        }
+    }
+}
+
+/// Validates commands as they arrive, so rejections are answered right away, accepted ones wait for their tick.
+fn handle_client_move_commands(
+    mut command_queue: EventReader<AssociatedCommand>,
+    query: Query<&PlayerControllable>,
+    entities: Res<NetworkEntityMap>,
+    mut net: ResMut<NetworkResource>,
+    mut validator: ResMut<CommandValidator>,
+    mut buffer: ResMut<CommandBuffer>,
+    tick: Res<GameTick>,
+) {
+    command_queue.iter().for_each(|(handle, player_id, stamped, controllable)| {
+        let PlayerCommand::PointerMoveChange(unit_id, _, sequence) = controllable;
+        //info!(target_unit = unit_id, query = ?query.iter_mut().collect::<Vec<(Mut<'_, Movable>, Mut<'_, PlayerControllable>, &NetworkSync)>>());
+        let unit = entities.get(unit_id.unique_id).and_then(|entity| query.get(entity).ok());
+        if let Some(unit) = unit {
+            let context = CommandContext { player_id: *player_id, unit };
+            match validator.validate(controllable, &context) {
+                Ok(_) => {
+                    let command = AcceptedCommand { handle: *handle, player_id: *player_id, command: *controllable };
+                    buffer.push(*stamped, tick.0, command);
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    // the player predicted the command already and has to roll it back
+                    if let Err(e) = net.send_message(*handle, GameEvent::CommandRejected(*sequence, e)) {
+                        error!("Failed to send command rejection to {}: {:?}", handle, e);
+                    }
+                }
+            }
+        } else {
+            warn!(msg = "Player tried to move unit X which is not movable or does not exist", player = player_id, unit = ?unit_id);
+        }
+    })
+}
+
+/// Applies the buffered commands due this tick, before units move.
+fn apply_due_commands(
+    mut buffer: ResMut<CommandBuffer>,
+    mut query: Query<(&mut Movable, &mut PlayerControllable)>,
+    entities: Res<NetworkEntityMap>,
+    mut accepted: EventWriter<AcceptedCommand>,
+    tick: Res<GameTick>,
+) {
+    for command in buffer.take_due(tick.0) {
+        let PlayerCommand::PointerMoveChange(unit_id, order, sequence) = command.command;
+        // the unit can be gone by now, its player left while the command waited
+        if let Some((mut movable, mut control)) = entities.get(unit_id.unique_id).and_then(|entity| query.get_mut(entity).ok()) {
+            movable.update(Movable::from(order));
+            if (sequence.wrapping_sub(control.last_input) as i32) > 0 {
+                control.last_input = sequence;
+            }
+            accepted.send(command);
+        }
+    }
+}
+
+fn broadcast_server_event(event_writer: &mut EventWriter<ServerEvent>, event: ServerEvent) {
+    // info!(sending_event = ?event);
+    event_writer.send(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::game::MoveOrder;
+
+    fn command(sequence: InputSequence) -> AcceptedCommand {
+        let order = MoveOrder::new(Vec2::ZERO);
+        let command = PlayerCommand::PointerMoveChange(NetworkSync { unique_id: 1 }, order, sequence);
+        AcceptedCommand { handle: 0, player_id: 1, command }
+    }
+
+    fn sequences(commands: Vec<AcceptedCommand>) -> Vec<InputSequence> {
+        commands
+            .into_iter()
+            .map(|AcceptedCommand { command: PlayerCommand::PointerMoveChange(_, _, sequence), .. }| sequence)
+            .collect()
+    }
+
+    #[test]
+    fn commands_wait_for_their_tick() {
+        let mut buffer = CommandBuffer::default();
+        buffer.push(12, 10, command(1));
+        assert!(buffer.take_due(11).is_empty());
+        assert_eq!(sequences(buffer.take_due(12)), vec![1]);
+        assert!(buffer.take_due(13).is_empty());
+    }
+
+    #[test]
+    fn late_commands_apply_at_the_current_tick() {
+        let mut buffer = CommandBuffer::default();
+        buffer.push(5, 10, command(1));
+        assert_eq!(sequences(buffer.take_due(10)), vec![1]);
+
+        buffer.push(Tick::MAX, 2, command(2));
+        assert_eq!(sequences(buffer.take_due(2)), vec![2]);
+    }
+
+    #[test]
+    fn commands_stamped_too_far_ahead_are_capped() {
+        let mut buffer = CommandBuffer::default();
+        buffer.push(10 + 10 * MAX_COMMAND_LEAD, 10, command(1));
+        assert!(buffer.take_due(10 + MAX_COMMAND_LEAD - 1).is_empty());
+        assert_eq!(sequences(buffer.take_due(10 + MAX_COMMAND_LEAD)), vec![1]);
+    }
+
+    #[test]
+    fn due_commands_come_out_by_tick_then_arrival() {
+        let mut buffer = CommandBuffer::default();
+        buffer.push(12, 10, command(1));
+        buffer.push(11, 10, command(2));
+        buffer.push(12, 10, command(3));
+        assert_eq!(sequences(buffer.take_due(20)), vec![2, 1, 3]);
+    }
+}
@@ -0,0 +1,274 @@
+use common::bevy::prelude::*;
+use common::bevy::utils::HashMap;
+use common::bevy_networking_turbulence::ConnectionHandle;
+use serde::Deserialize;
+
+/// How often the dropped message counters get logged, in seconds
+const METRICS_INTERVAL: f64 = 10.0;
+
+/// Message budgets every client gets, per kind of message.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub commands_per_second: f64,
+    /// Commands a client may send at once after being quiet
+    pub command_burst: f64,
+    pub meta_per_second: f64,
+    pub meta_burst: f64,
+    /// Clients going over budget this many seconds in a row get kicked, 0 never kicks
+    pub kick_after_seconds: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            commands_per_second: 30.0,
+            command_burst: 60.0,
+            meta_per_second: 10.0,
+            meta_burst: 20.0,
+            kick_after_seconds: 3,
+        }
+    }
+}
+
+pub enum MessageKind {
+    Command,
+    Meta,
+}
+
+/// What to do with a received message.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    /// Drop it and kick the client, given once per client
+    Kick,
+}
+
+/// Holds up to `capacity` tokens and regains `refill_per_second` of them, every message takes one.
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_second: f64,
+    tokens: f64,
+    updated_at: f64,
+}
+
+impl TokenBucket {
+    pub fn new(refill_per_second: f64, capacity: f64, now: f64) -> Self {
+        TokenBucket { capacity, refill_per_second, tokens: capacity, updated_at: now }
+    }
+
+    pub fn try_take(&mut self, now: f64) -> bool {
+        let elapsed = (now - self.updated_at).max(0.0);
+        self.tokens = (self.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        self.updated_at = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct ClientLimits {
+    commands: TokenBucket,
+    meta: TokenBucket,
+    /// Last whole second in which a message was dropped
+    last_violation: Option<u64>,
+    /// Seconds in a row with dropped messages
+    streak: u32,
+    kicked: bool,
+}
+
+impl ClientLimits {
+    fn new(config: &RateLimitConfig, now: f64) -> Self {
+        ClientLimits {
+            commands: TokenBucket::new(config.commands_per_second, config.command_burst, now),
+            meta: TokenBucket::new(config.meta_per_second, config.meta_burst, now),
+            last_violation: None,
+            streak: 0,
+            kicked: false,
+        }
+    }
+}
+
+/// Totals since the server started.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RateLimitMetrics {
+    pub dropped_commands: u64,
+    pub dropped_meta: u64,
+    pub flood_kicks: u64,
+}
+
+/// Message budgets of every connection, created on their first message.
+#[derive(Default)]
+pub struct RateLimits {
+    clients: HashMap<ConnectionHandle, ClientLimits>,
+    pub metrics: RateLimitMetrics,
+}
+
+impl RateLimits {
+    /// Takes a token for a message `handle` sent, messages over budget are dropped.
+    pub fn check(&mut self, handle: ConnectionHandle, kind: MessageKind, config: &RateLimitConfig, now: f64) -> Verdict {
+        let limits = self.clients.entry(handle).or_insert_with(|| ClientLimits::new(config, now));
+        let allowed = match kind {
+            MessageKind::Command => limits.commands.try_take(now),
+            MessageKind::Meta => limits.meta.try_take(now),
+        };
+        if allowed {
+            return Verdict::Allow;
+        }
+        match kind {
+            MessageKind::Command => self.metrics.dropped_commands += 1,
+            MessageKind::Meta => self.metrics.dropped_meta += 1,
+        }
+
+        let second = now as u64;
+        limits.streak = match limits.last_violation {
+            Some(last) if last == second => limits.streak,
+            Some(last) if last + 1 == second => limits.streak + 1,
+            _ => 1,
+        };
+        limits.last_violation = Some(second);
+        if config.kick_after_seconds > 0 && limits.streak >= config.kick_after_seconds && !limits.kicked {
+            limits.kicked = true;
+            self.metrics.flood_kicks += 1;
+            Verdict::Kick
+        } else {
+            Verdict::Drop
+        }
+    }
+
+    pub fn forget(&mut self, handle: ConnectionHandle) {
+        self.clients.remove(&handle);
+    }
+}
+
+pub fn report_rate_limits(
+    limits: Res<RateLimits>,
+    time: Res<Time>,
+    mut reported: Local<(f64, RateLimitMetrics)>,
+) {
+    let now = time.seconds_since_startup();
+    let (reported_at, last) = &mut *reported;
+    if now - *reported_at < METRICS_INTERVAL || limits.metrics == *last {
+        return;
+    }
+    *reported_at = now;
+    *last = limits.metrics.clone();
+    let metrics = &limits.metrics;
+    warn!(
+        dropped_commands = metrics.dropped_commands,
+        dropped_meta = metrics.dropped_meta,
+        flood_kicks = metrics.flood_kicks,
+        "Clients went over their message budgets"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(kick_after_seconds: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            commands_per_second: 2.0,
+            command_burst: 2.0,
+            meta_per_second: 1.0,
+            meta_burst: 1.0,
+            kick_after_seconds,
+        }
+    }
+
+    /// Sends commands from `handle` until one of them is not allowed, returns what happened to it.
+    fn flood(limits: &mut RateLimits, handle: ConnectionHandle, config: &RateLimitConfig, now: f64) -> Verdict {
+        loop {
+            match limits.check(handle, MessageKind::Command, config, now) {
+                Verdict::Allow => continue,
+                verdict => return verdict,
+            }
+        }
+    }
+
+    #[test]
+    fn buckets_start_full_and_refill_over_time() {
+        let mut bucket = TokenBucket::new(2.0, 3.0, 10.0);
+        assert!((0..3).all(|_| bucket.try_take(10.0)));
+        assert!(!bucket.try_take(10.0));
+        assert!(!bucket.try_take(10.25));
+        assert!(bucket.try_take(10.5));
+        assert!(!bucket.try_take(10.5));
+    }
+
+    #[test]
+    fn buckets_never_hold_more_than_their_capacity() {
+        let mut bucket = TokenBucket::new(2.0, 3.0, 0.0);
+        assert!(bucket.try_take(100.0));
+        assert!(bucket.try_take(100.0));
+        assert!(bucket.try_take(100.0));
+        assert!(!bucket.try_take(100.0));
+    }
+
+    #[test]
+    fn clocks_going_backwards_refill_nothing() {
+        let mut bucket = TokenBucket::new(2.0, 1.0, 10.0);
+        assert!(bucket.try_take(10.0));
+        assert!(!bucket.try_take(5.0));
+        assert!(!bucket.try_take(5.4));
+    }
+
+    #[test]
+    fn kinds_and_clients_have_their_own_budgets() {
+        let config = config(0);
+        let mut limits = RateLimits::default();
+        assert_eq!(flood(&mut limits, 1, &config, 0.0), Verdict::Drop);
+        assert_eq!(limits.check(1, MessageKind::Meta, &config, 0.0), Verdict::Allow);
+        assert_eq!(limits.check(2, MessageKind::Command, &config, 0.0), Verdict::Allow);
+        assert_eq!(limits.check(1, MessageKind::Meta, &config, 0.0), Verdict::Drop);
+        assert_eq!(limits.metrics, RateLimitMetrics { dropped_commands: 1, dropped_meta: 1, flood_kicks: 0 });
+    }
+
+    #[test]
+    fn flooding_seconds_in_a_row_gets_kicked_once() {
+        let config = config(3);
+        let mut limits = RateLimits::default();
+        assert_eq!(flood(&mut limits, 1, &config, 0.1), Verdict::Drop);
+        // more drops within the same second do not count again
+        assert_eq!(flood(&mut limits, 1, &config, 0.9), Verdict::Drop);
+        assert_eq!(flood(&mut limits, 1, &config, 1.5), Verdict::Drop);
+        assert_eq!(flood(&mut limits, 1, &config, 2.5), Verdict::Kick);
+        assert_eq!(flood(&mut limits, 1, &config, 3.5), Verdict::Drop);
+        assert_eq!(limits.metrics.flood_kicks, 1);
+    }
+
+    #[test]
+    fn quiet_seconds_reset_the_streak() {
+        let config = config(3);
+        let mut limits = RateLimits::default();
+        assert_eq!(flood(&mut limits, 1, &config, 0.5), Verdict::Drop);
+        assert_eq!(flood(&mut limits, 1, &config, 1.5), Verdict::Drop);
+        assert_eq!(flood(&mut limits, 1, &config, 3.5), Verdict::Drop);
+        assert_eq!(flood(&mut limits, 1, &config, 4.5), Verdict::Drop);
+        assert_eq!(flood(&mut limits, 1, &config, 5.5), Verdict::Kick);
+    }
+
+    #[test]
+    fn zero_never_kicks() {
+        let config = config(0);
+        let mut limits = RateLimits::default();
+        for second in 0..10 {
+            assert_eq!(flood(&mut limits, 1, &config, second as f64), Verdict::Drop);
+        }
+        assert_eq!(limits.metrics.flood_kicks, 0);
+    }
+
+    #[test]
+    fn forgotten_clients_start_over() {
+        let config = config(1);
+        let mut limits = RateLimits::default();
+        assert_eq!(flood(&mut limits, 1, &config, 0.0), Verdict::Kick);
+        limits.forget(1);
+        assert_eq!(limits.check(1, MessageKind::Command, &config, 0.0), Verdict::Allow);
+        assert_eq!(flood(&mut limits, 1, &config, 0.0), Verdict::Kick);
+    }
+}
@@ -0,0 +1,80 @@
+use common::bevy::utils::HashMap;
+use common::bevy_networking_turbulence::ConnectionHandle;
+use common::events::PlayerId;
+use common::get_random;
+use common::protocol::SessionToken;
+
+struct Session {
+    player_id: PlayerId,
+    name: String,
+    /// The connection currently or last attached to the session
+    handle: ConnectionHandle,
+    /// None while a connection is attached
+    expires_at: Option<f64>,
+}
+
+/// Players by session token, so a reconnecting client gets its old `PlayerId` and pointer back.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: HashMap<SessionToken, Session>,
+}
+
+impl Sessions {
+    /// Starts a session for a new player and returns its token.
+    pub fn open(&mut self, player_id: PlayerId, name: String, handle: ConnectionHandle) -> SessionToken {
+        let token = ((get_random() as u64) << 32) | get_random() as u64;
+        self.sessions.insert(token, Session { player_id, name, handle, expires_at: None });
+        token
+    }
+
+    /// The session of a player, attached or not.
+    pub fn find(&self, player_id: PlayerId) -> Option<SessionToken> {
+        self.sessions
+            .iter()
+            .find(|(_, session)| session.player_id == player_id)
+            .map(|(token, _)| *token)
+    }
+
+    /// Attaches `handle` to the session, returns its player and the connection it is taken over from, if that one is still attached.
+    pub fn resume(&mut self, token: SessionToken, handle: ConnectionHandle) -> Option<(PlayerId, String, Option<ConnectionHandle>)> {
+        let session = self.sessions.get_mut(&token)?;
+        let previous = match session.expires_at {
+            None => Some(session.handle),
+            Some(_) => None,
+        };
+        session.handle = handle;
+        session.expires_at = None;
+        Some((session.player_id, session.name.clone(), previous))
+    }
+
+    /// The connection dropped, the session can be resumed for `grace` seconds.
+    pub fn detach(&mut self, handle: ConnectionHandle, now: f64, grace: f64) {
+        if let Some(session) = self.attached_mut(handle) {
+            session.expires_at = Some(now + grace);
+        }
+    }
+
+    /// Ends the session attached to `handle` for good.
+    pub fn end(&mut self, handle: ConnectionHandle) {
+        self.sessions.retain(|_, session| session.expires_at.is_some() || session.handle != handle);
+    }
+
+    /// Ends the sessions nobody came back for, returns their last connection and player.
+    pub fn expire(&mut self, now: f64) -> Vec<(ConnectionHandle, PlayerId)> {
+        let mut expired = Vec::new();
+        self.sessions.retain(|_, session| match session.expires_at {
+            Some(expires_at) if now >= expires_at => {
+                expired.push((session.handle, session.player_id));
+                false
+            }
+            _ => true,
+        });
+        expired
+    }
+
+    fn attached_mut(&mut self, handle: ConnectionHandle) -> Option<&mut Session> {
+        self.sessions
+            .values_mut()
+            .find(|session| session.expires_at.is_none() && session.handle == handle)
+    }
+}
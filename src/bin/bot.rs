@@ -0,0 +1,393 @@
+//! Load test bots: hundreds of headless clients in one process, all playing against one server.
+//! `cargo run --release --bin bot -- --help` lists the options.
+use clap::{value_t, App as Args, Arg, ArgMatches};
+use client::{ClientPlugin, ConnectRequest, ConnectionState, MessageCounts, PlayerToken, PointerClick};
+use common::auth::{self, TokenClaims};
+use common::bevy::app::Events;
+use common::bevy::prelude::*;
+use common::bevy::tasks::{AsyncComputeTaskPool, ComputeTaskPool, IoTaskPool, TaskPool, TaskPoolBuilder};
+use common::bevy::utils::HashMap;
+use common::clock::ClockSync;
+use common::validation::{WORLD_HEIGHT, WORLD_WIDTH};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::mem::ManuallyDrop;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Player ids of bots signing their own tokens start here, out of the way of real players
+const FIRST_BOT_ID: u32 = 1_000_000;
+
+fn args<'a>(server: &'a str) -> Args<'a, 'a> {
+    let flag = |name: &'a str, default: &'a str, help: &'a str| {
+        Arg::with_name(name).long(name).takes_value(true).default_value(default).help(help)
+    };
+    Args::new("bot")
+        .about("Simulated players for load testing a server")
+        .arg(flag("server", server, "Server to connect to, as host:port"))
+        .arg(flag("players", "100", "Bots to run"))
+        .arg(flag("rate", "1", "Move commands every bot sends per second, 0 for none"))
+        .arg(flag("duration", "30", "Seconds to keep playing once every bot started connecting"))
+        .arg(flag("ramp-up", "5", "Seconds over which the bots start connecting, one after another"))
+        .arg(flag("fps", "60", "Frames per second of every bot"))
+        .arg(flag("threads", "4", "Threads the bots are spread over"))
+        .arg(Arg::with_name("script").long("script").takes_value(true)
+            .help("File with one `x y` target per line, bots go through it in turn instead of picking random targets"))
+        .arg(Arg::with_name("auth-secret").long("auth-secret").takes_value(true)
+            .help("Secret of a server requiring tokens, every bot signs its own"))
+}
+
+struct Options {
+    server: String,
+    players: usize,
+    rate: f64,
+    duration: Duration,
+    ramp_up: Duration,
+    fps: f64,
+    threads: usize,
+    script: Option<Arc<Vec<Vec2>>>,
+    auth_secret: Option<String>,
+}
+
+impl Options {
+    fn from_args(args: &ArgMatches) -> Result<Self, String> {
+        fn value<T: FromStr>(args: &ArgMatches, name: &str) -> T {
+            value_t!(args, name, T).unwrap_or_else(|e| e.exit())
+        }
+        let script = match args.value_of("script") {
+            Some(path) => Some(Arc::new(read_script(Path::new(path))?)),
+            None => None,
+        };
+        Ok(Options {
+            server: value(args, "server"),
+            players: value(args, "players"),
+            rate: value(args, "rate"),
+            duration: Duration::from_secs_f64(value(args, "duration")),
+            ramp_up: Duration::from_secs_f64(value(args, "ramp-up")),
+            fps: value::<f64>(args, "fps").max(1.0),
+            threads: value::<usize>(args, "threads").max(1),
+            script,
+            auth_secret: args.value_of("auth-secret").map(str::to_string),
+        })
+    }
+
+    /// When bot `index` starts connecting, counted from the start of the run.
+    fn start_of(&self, index: usize) -> Duration {
+        self.ramp_up.mul_f64(index as f64 / self.players as f64)
+    }
+}
+
+fn read_script(path: &Path) -> Result<Vec<Vec2>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read script {:?}: {}", path, e))?;
+    let targets = text
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let coordinates: Vec<f32> = line.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+            match coordinates[..] {
+                [x, y] => Ok(Vec2::new(x, y)),
+                _ => Err(format!("Script {:?} line {}: expected `x y`, got {:?}", path, number + 1, line)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if targets.is_empty() {
+        return Err(format!("Script {:?} has no targets", path));
+    }
+    Ok(targets)
+}
+
+/// Where a bot clicks next.
+enum Targets {
+    Random(Box<StdRng>),
+    /// The script and the position in it
+    Script(Arc<Vec<Vec2>>, usize),
+}
+
+impl Targets {
+    fn next(&mut self) -> Vec2 {
+        match self {
+            Targets::Random(rng) => Vec2::new(rng.gen_range(0.0..WORLD_WIDTH), rng.gen_range(0.0..WORLD_HEIGHT)),
+            Targets::Script(targets, position) => {
+                let target = targets[*position % targets.len()];
+                *position += 1;
+                target
+            }
+        }
+    }
+}
+
+struct Orders {
+    targets: Targets,
+    /// Seconds between two clicks
+    interval: f64,
+    next_at: f64,
+}
+
+/// Clicks somewhere every `interval` while connected, which turns into a move command.
+fn issue_orders(
+    mut orders: ResMut<Orders>,
+    state: Res<ConnectionState>,
+    time: Res<Time>,
+    mut clicks: EventWriter<PointerClick>,
+) {
+    let now = time.seconds_since_startup();
+    if *state != ConnectionState::Connected || now < orders.next_at {
+        return;
+    }
+    orders.next_at = now + orders.interval;
+    let target = orders.targets.next();
+    clicks.send(PointerClick(target));
+}
+
+/// The bots share task pools, one set of pools per bot would mean thousands of threads.
+#[derive(Clone)]
+struct Pools {
+    io: TaskPool,
+    compute: TaskPool,
+    async_compute: TaskPool,
+}
+
+impl Pools {
+    fn new() -> Self {
+        let pool = |name: &str| TaskPoolBuilder::new().thread_name(format!("bot {}", name)).build();
+        Pools { io: pool("io"), compute: pool("compute"), async_compute: pool("async compute") }
+    }
+}
+
+/// How far the bots got, for the progress line.
+#[derive(Default)]
+struct Progress {
+    connected: AtomicUsize,
+    failed: AtomicUsize,
+}
+
+#[derive(Default)]
+struct BotReport {
+    /// Seconds from starting to connect until the server identified the bot
+    connected_after: Option<f64>,
+    /// Why the server would not have the bot, if it said so
+    failure: Option<String>,
+    /// Times the connection dropped after being established
+    drops: u32,
+    /// Round trip time of every pong, in seconds
+    rtts: Vec<f64>,
+    counts: MessageCounts,
+}
+
+/// One simulated player. Apps are never dropped, see `tests/harness`.
+struct Bot {
+    app: ManuallyDrop<App>,
+    starts_at: Duration,
+    started: bool,
+    connected: bool,
+    /// Pongs recorded by the clock so far, pings go out once a second so an update sees one at most
+    pongs: u32,
+    report: BotReport,
+}
+
+impl Bot {
+    fn new(index: usize, options: &Options, pools: &Pools) -> Self {
+        let token = options.auth_secret.as_ref().map(|secret| {
+            let claims = TokenClaims { player_id: FIRST_BOT_ID + index as u32, name: format!("bot {}", index), expires_at: None };
+            auth::sign(&claims, secret.as_bytes())
+        });
+        let targets = match &options.script {
+            Some(script) => Targets::Script(script.clone(), index),
+            None => Targets::Random(Box::new(StdRng::seed_from_u64(index as u64))),
+        };
+        // spread the clicks of all bots over the interval
+        let interval = 1.0 / options.rate;
+        let next_at = if interval.is_finite() { interval * rand::thread_rng().gen::<f64>() } else { f64::INFINITY };
+
+        let mut builder = App::build();
+        builder
+            .insert_resource(IoTaskPool(pools.io.clone()))
+            .insert_resource(ComputeTaskPool(pools.compute.clone()))
+            .insert_resource(AsyncComputeTaskPool(pools.async_compute.clone()))
+            .insert_resource(PlayerToken(token))
+            .insert_resource(Orders { targets, interval, next_at })
+            .add_plugins(MinimalPlugins)
+            .add_plugin(ClientPlugin { headless: true, ..Default::default() })
+            .add_system(issue_orders.system().before("pointer_commands"));
+        Bot {
+            app: ManuallyDrop::new(builder.app),
+            starts_at: options.start_of(index),
+            started: false,
+            connected: false,
+            pongs: 0,
+            report: BotReport::default(),
+        }
+    }
+
+    /// `elapsed` is the time since the run started.
+    fn step(&mut self, elapsed: Duration, server: &str, progress: &Progress) {
+        if !self.started {
+            if elapsed < self.starts_at {
+                return;
+            }
+            self.started = true;
+            let mut requests = self.app.world.get_resource_mut::<Events<ConnectRequest>>().unwrap();
+            requests.send(ConnectRequest(server.to_string()));
+        }
+        self.app.update();
+
+        let clock = self.app.world.get_resource::<ClockSync>().unwrap();
+        let report = &mut self.report;
+        if clock.samples != self.pongs {
+            self.pongs = clock.samples;
+            report.rtts.push(clock.last_rtt);
+        }
+
+        let state = self.app.world.get_resource::<ConnectionState>().unwrap();
+        match state {
+            ConnectionState::Connected if !self.connected => {
+                self.connected = true;
+                if report.connected_after.is_none() {
+                    report.connected_after = Some((elapsed - self.starts_at).as_secs_f64());
+                    progress.connected.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            ConnectionState::Connected => {}
+            _ if self.connected => {
+                self.connected = false;
+                report.drops += 1;
+            }
+            _ if report.connected_after.is_none() && report.failure.is_none() => {
+                // lost connections get retried, anything else is the final word
+                report.failure = match state {
+                    ConnectionState::Failed(e) => Some(e.to_string()),
+                    ConnectionState::Disconnected(Some(reason)) if !state.should_reconnect() => Some(reason.to_string()),
+                    _ => None,
+                };
+                if report.failure.is_some() {
+                    progress.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn finish(mut self) -> BotReport {
+        self.report.counts = *self.app.world.get_resource::<MessageCounts>().unwrap();
+        self.report
+    }
+}
+
+/// Runs bots `first`, `first + step`, ... until the run is over. Apps cannot move between threads,
+/// so they are built here.
+fn run_bots(first: usize, step: usize, options: Arc<Options>, pools: Pools, started: Instant, progress: Arc<Progress>) -> Vec<BotReport> {
+    let mut bots: Vec<_> = (first..options.players).step_by(step).map(|index| Bot::new(index, &options, &pools)).collect();
+    let frame = Duration::from_secs_f64(1.0 / options.fps);
+    let end = options.ramp_up + options.duration;
+    loop {
+        let frame_start = Instant::now();
+        let elapsed = started.elapsed();
+        if elapsed >= end {
+            break;
+        }
+        for bot in bots.iter_mut() {
+            bot.step(elapsed, &options.server, &progress);
+        }
+        if let Some(rest) = frame.checked_sub(frame_start.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+    bots.into_iter().map(Bot::finish).collect()
+}
+
+/// The value below which `percent` of the sorted `values` lie.
+fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    let index = ((sorted.len() - 1) as f64 * percent / 100.0).round() as usize;
+    sorted[index]
+}
+
+fn print_report(reports: &[BotReport], elapsed: Duration) {
+    let connected: Vec<f64> = reports.iter().filter_map(|report| report.connected_after).collect();
+    let mut failures: HashMap<&str, usize> = HashMap::default();
+    for report in reports.iter().filter(|report| report.connected_after.is_none()) {
+        *failures.entry(report.failure.as_deref().unwrap_or("No answer in time")).or_default() += 1;
+    }
+
+    println!();
+    println!("{} bots, {} connected, {} did not", reports.len(), connected.len(), reports.len() - connected.len());
+    if !connected.is_empty() {
+        let average = connected.iter().sum::<f64>() / connected.len() as f64;
+        println!("  Connecting took {:.0} ms on average", average * 1000.0);
+    }
+    let mut failures: Vec<_> = failures.into_iter().collect();
+    failures.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    for (reason, count) in failures {
+        println!("  {} x {}", count, reason);
+    }
+    let drops: u32 = reports.iter().map(|report| report.drops).sum();
+    if drops > 0 {
+        println!("  {} connections dropped after connecting", drops);
+    }
+
+    let mut rtts: Vec<f64> = reports.iter().flat_map(|report| report.rtts.iter().copied()).collect();
+    rtts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    if rtts.is_empty() {
+        println!("RTT: no samples");
+    } else {
+        let ms = |percent| percentile(&rtts, percent) * 1000.0;
+        println!(
+            "RTT: p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, max {:.1} ms ({} samples)",
+            ms(50.0), ms(90.0), ms(99.0), ms(100.0), rtts.len()
+        );
+    }
+
+    let seconds = elapsed.as_secs_f64();
+    let sent: u64 = reports.iter().map(|report| report.counts.sent).sum();
+    let received: u64 = reports.iter().map(|report| report.counts.received).sum();
+    println!(
+        "Messages: {:.0}/s sent, {:.0}/s received, over {:.1} s",
+        sent as f64 / seconds, received as f64 / seconds, seconds
+    );
+}
+
+fn main() {
+    // same as the client, servers listening on every interface answer from this address
+    let ip = common::bevy_networking_turbulence::find_my_ip_address().unwrap_or_else(|| Ipv4Addr::LOCALHOST.into());
+    let server = SocketAddr::new(ip, common::SERVER_PORT).to_string();
+    let options = match Options::from_args(&args(&server).get_matches()) {
+        Ok(options) => Arc::new(options),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    println!(
+        "Starting {} bots against {} over {:.1} s, each sending {} commands per second",
+        options.players, options.server, options.ramp_up.as_secs_f64(), options.rate
+    );
+    let pools = Pools::new();
+    let progress = Arc::new(Progress::default());
+    let started = Instant::now();
+    let threads: Vec<_> = (0..options.threads)
+        .map(|first| {
+            let (options, pools, progress) = (options.clone(), pools.clone(), progress.clone());
+            thread::spawn(move || run_bots(first, options.threads, options, pools, started, progress))
+        })
+        .collect();
+
+    let end = options.ramp_up + options.duration;
+    while started.elapsed() < end {
+        thread::sleep(Duration::from_secs(1));
+        println!(
+            "{:>6.1} s  {} connected, {} refused",
+            started.elapsed().as_secs_f64(),
+            progress.connected.load(Ordering::Relaxed),
+            progress.failed.load(Ordering::Relaxed)
+        );
+    }
+    let reports: Vec<BotReport> = threads.into_iter().flat_map(|thread| thread.join().expect("bot thread panicked")).collect();
+    print_report(&reports, started.elapsed());
+}
@@ -1,8 +1,3 @@
-/*use client;
-
 fn main() {
     client::main();
 }
-
-
- */
\ No newline at end of file
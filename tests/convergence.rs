@@ -0,0 +1,81 @@
+mod harness;
+
+use common::bevy::prelude::*;
+use common::events::{ServerEvent, Tick};
+use common::game::{GameTick, Movable};
+use common::replication::Replicate;
+use harness::{pointers, Harness};
+
+#[test]
+fn clients_see_every_pointer() {
+    let mut harness = Harness::new(3);
+    harness.connect_all();
+
+    let players: Vec<_> = (0..3).map(|client| harness.player_id(client)).collect();
+    let everywhere = harness.run_until(500, |harness| {
+        harness.apps().all(|app| {
+            let owners: Vec<_> = pointers(app).iter().map(|(owner, _, _)| *owner).collect();
+            owners.len() == players.len() && players.iter().all(|player| owners.contains(player))
+        })
+    });
+    assert!(everywhere, "not every app has one pointer per player");
+}
+
+#[test]
+fn clicks_move_pointers_on_every_app() {
+    let mut harness = Harness::new(2);
+    harness.connect_all();
+    let spawned = harness.run_until(500, |harness| harness.apps().all(|app| pointers(app).len() == 2));
+    assert!(spawned, "not every app has both pointers");
+
+    let targets = [Vec2::new(200.0, 150.0), Vec2::new(120.0, 300.0)];
+    for (client, target) in targets.iter().enumerate() {
+        harness.click(client, *target);
+    }
+    let players = [harness.player_id(0), harness.player_id(1)];
+
+    // the farther target is 260 units, 156 ticks away
+    let converged = harness.run_until(1000, |harness| {
+        harness.apps().all(|app| {
+            pointers(app).iter().all(|(owner, location, movable)| {
+                let target = targets[players.iter().position(|player| player == owner).unwrap()];
+                location.distance(target) < 1.0 && !movable.is_active()
+            })
+        })
+    });
+    assert!(converged, "pointers did not settle on their targets everywhere");
+}
+
+#[derive(Default)]
+struct MovableUpdates(Vec<Tick>);
+
+fn count_movable_updates(mut events: EventReader<ServerEvent>, tick: Res<GameTick>, mut updates: ResMut<MovableUpdates>) {
+    for event in events.iter() {
+        if let ServerEvent::ComponentUpdate(_, Movable::ID, _) = event {
+            updates.0.push(tick.0);
+        }
+    }
+}
+
+#[test]
+fn moving_pointers_are_replicated_on_order_and_arrival_only() {
+    let mut harness = Harness::with_server(1, |server| {
+        server.init_resource::<MovableUpdates>().add_system(count_movable_updates.system());
+    });
+    harness.connect_all();
+    assert!(harness.run_until(500, |harness| pointers(&mut harness.clients[0]).len() == 1));
+    harness.server.world.get_resource_mut::<MovableUpdates>().unwrap().0.clear();
+
+    // 100 units from the spawn point, 60 ticks away
+    harness.click(0, Vec2::new(150.0, 50.0));
+    let arrived = harness.run_until(500, |harness| {
+        pointers(&mut harness.server).iter().all(|(_, location, movable)| {
+            location.distance(Vec2::new(150.0, 50.0)) < 1.0 && !movable.is_active()
+        })
+    });
+    assert!(arrived, "the pointer did not arrive");
+
+    let updates = &harness.server.world.get_resource::<MovableUpdates>().unwrap().0;
+    assert_eq!(updates.len(), 2, "expected one update for the order and one for the arrival: {:?}", updates);
+    assert!(updates[1].wrapping_sub(updates[0]) >= 59, "the pointer did not move for long: {:?}", updates);
+}
@@ -0,0 +1,202 @@
+//! Runs a server and any number of headless clients in one process, talking over loopback.
+//!
+//! Apps step in lockstep: every step runs exactly one simulation tick on each app, then lets the
+//! network settle until every packet sent has arrived, so how far the world got only depends on
+//! the number of steps taken. Turbulence keeps its heartbeats and timeouts on the wall clock, those
+//! still take real time.
+//!
+//! Apps are never dropped. Dropping a connection drops its channels before the task sending their
+//! packets, which can then unwrap a closed channel on the IO pool (turbulence 0.3, `transport.rs`)
+//! and abort the whole test binary. `keep` wraps apps so they are left alone instead.
+// every test binary uses a different part of the harness
+#![allow(dead_code)]
+use client::{ClientPlugin, ConnectRequest, ConnectionState, PointerClick};
+use common::bevy::app::Events;
+use common::bevy::prelude::*;
+use common::bevy_networking_turbulence::NetworkResource;
+use common::events::PlayerId;
+use common::game::{Location, ManualTicks, Movable, PlayerControllable};
+use common::protocol::{ClientIdentification, NetworkConfig};
+use server::{ServerConfig, ServerPlugin};
+use std::mem::ManuallyDrop;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Pause between two looks at the network while it settles
+const SETTLE_POLL: Duration = Duration::from_millis(1);
+/// Looks in a row without packets in flight before the network counts as settled
+const SETTLED_AFTER: usize = 3;
+/// Looks before giving up on packets that were lost or went to a closed connection
+const SETTLE_LIMIT: usize = 200;
+
+/// Leaves `app` alone when it goes out of scope, see the module docs for why.
+pub fn keep(app: App) -> ManuallyDrop<App> {
+    ManuallyDrop::new(app)
+}
+
+/// Packets the apps sent that none of them received yet, going by every connection's stats.
+fn in_flight(apps: &mut [&mut App]) -> i64 {
+    apps.iter()
+        .flat_map(|app| app.world.get_resource::<NetworkResource>().unwrap().connections.values())
+        .map(|connection| connection.stats())
+        .map(|stats| stats.packets_tx as i64 - stats.packets_rx as i64)
+        .sum()
+}
+
+/// Updates the apps until what they sent has arrived. Updates do not tick, unless ticks were asked for.
+pub fn settle(apps: &mut [&mut App]) {
+    let mut quiet = 0;
+    for _ in 0..SETTLE_LIMIT {
+        sleep(SETTLE_POLL);
+        for app in apps.iter_mut() {
+            app.update();
+        }
+        quiet = if in_flight(apps) == 0 { quiet + 1 } else { 0 };
+        if quiet == SETTLED_AFTER {
+            return;
+        }
+    }
+}
+
+/// Updates the apps until `done` holds, false if it did not within `limit`. Only for what turbulence
+/// times on the wall clock, the simulation is stepped with `Harness::step`.
+pub fn wait(apps: &mut [&mut App], limit: Duration, mut done: impl FnMut(&mut [&mut App]) -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < limit {
+        sleep(SETTLE_POLL);
+        for app in apps.iter_mut() {
+            app.update();
+        }
+        if done(apps) {
+            return true;
+        }
+    }
+    false
+}
+
+pub struct Harness {
+    pub server: ManuallyDrop<App>,
+    pub clients: Vec<ManuallyDrop<App>>,
+}
+
+impl Harness {
+    /// Starts a server on a free port and `clients` clients connecting to it.
+    pub fn new(clients: usize) -> Self {
+        Self::with_server(clients, |_| {})
+    }
+
+    /// Like `new`, `extend` gets to add game systems and rules to the server first.
+    pub fn with_server(clients: usize, extend: impl FnOnce(&mut AppBuilder)) -> Self {
+        Self::with_apps(clients, extend, |_| {})
+    }
+
+    /// Like `with_server`, `extend_client` gets to add to every client as well. Events only live for
+    /// two updates and a step takes several, systems added here see every one of them.
+    pub fn with_apps(clients: usize, extend: impl FnOnce(&mut AppBuilder), extend_client: impl Fn(&mut AppBuilder)) -> Self {
+        Self::build(clients, NetworkConfig::default(), extend, extend_client)
+    }
+
+    /// Like `new`, with heartbeats and timeouts of `network` on the server and every client.
+    pub fn with_network(clients: usize, network: NetworkConfig) -> Self {
+        Self::build(clients, network, |_| {}, |_| {})
+    }
+
+    fn build(
+        clients: usize,
+        network: NetworkConfig,
+        extend: impl FnOnce(&mut AppBuilder),
+        extend_client: impl Fn(&mut AppBuilder),
+    ) -> Self {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = ServerConfig {
+            bind: Ipv4Addr::LOCALHOST.into(),
+            port,
+            id_seed: Some(0),
+            network: network.clone(),
+            ..Default::default()
+        };
+        let address = config.address();
+
+        let mut builder = App::build();
+        builder.add_plugins(MinimalPlugins).insert_resource(ManualTicks::default());
+        builder.add_plugin(ServerPlugin { config });
+        extend(&mut builder);
+        let mut server = builder.app;
+        // listen before anyone connects
+        server.update();
+
+        let clients = (0..clients)
+            .map(|_| {
+                let mut builder = App::build();
+                builder
+                    .add_plugins(MinimalPlugins)
+                    .insert_resource(ManualTicks::default())
+                    .add_plugin(ClientPlugin { headless: true, network: network.clone() });
+                extend_client(&mut builder);
+                let mut app = builder.app;
+                send(&mut app, ConnectRequest(address.to_string()));
+                keep(app)
+            })
+            .collect();
+
+        Harness { server: keep(server), clients }
+    }
+
+    /// One simulation tick on the server, then on every client in order, then waits for the network.
+    pub fn step(&mut self) {
+        for app in self.apps() {
+            app.world.get_resource_mut::<ManualTicks>().unwrap().0 += 1;
+            app.update();
+        }
+        settle(&mut self.apps().collect::<Vec<_>>());
+    }
+
+    /// Steps until `done` holds, false if it did not within `steps` steps.
+    pub fn run_until(&mut self, steps: usize, mut done: impl FnMut(&mut Harness) -> bool) -> bool {
+        for _ in 0..steps {
+            self.step();
+            if done(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Panics unless every client got identified by the server in time.
+    pub fn connect_all(&mut self) {
+        let connected = self.run_until(500, |harness| {
+            harness.clients.iter().all(|client| {
+                *client.world.get_resource::<ConnectionState>().unwrap() == ConnectionState::Connected
+            })
+        });
+        assert!(connected, "clients did not connect in time");
+    }
+
+    pub fn player_id(&self, client: usize) -> PlayerId {
+        self.clients[client].world.get_resource::<ClientIdentification>().unwrap().player_id
+    }
+
+    /// Clicks into the window of `client`, as if it had one.
+    pub fn click(&mut self, client: usize, target: Vec2) {
+        send(&mut self.clients[client], PointerClick(target));
+    }
+
+    /// The server first, then every client.
+    pub fn apps(&mut self) -> impl Iterator<Item = &mut App> {
+        std::iter::once(&mut *self.server).chain(self.clients.iter_mut().map(|client| &mut **client))
+    }
+}
+
+fn send<E: Send + Sync + 'static>(app: &mut App, event: E) {
+    app.world.get_resource_mut::<Events<E>>().unwrap().send(event);
+}
+
+/// Location and movable of every pointer in the app, by owner.
+pub fn pointers(app: &mut App) -> Vec<(PlayerId, Vec2, Movable)> {
+    let mut query = app.world.query::<(&PlayerControllable, &Location, &Movable)>();
+    query
+        .iter(&app.world)
+        .map(|(control, location, movable)| (control.owner, location.0, *movable))
+        .collect()
+}
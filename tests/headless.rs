@@ -0,0 +1,22 @@
+mod harness;
+
+use client::ClientPlugin;
+use common::bevy::prelude::*;
+use common::game::GameInfo;
+use harness::keep;
+
+/// Without the render plugins there is no window, drawing systems would panic on the missing resources.
+#[test]
+fn headless_client_has_no_drawing_systems() {
+    let mut builder = App::build();
+    builder
+        .add_plugins(MinimalPlugins)
+        .add_plugin(ClientPlugin { headless: true, ..Default::default() });
+    let mut app = keep(builder.app);
+    app.update();
+
+    // systems that were only paused would start drawing now
+    app.world.get_resource_mut::<GameInfo>().unwrap().headless = false;
+    app.update();
+    app.update();
+}
@@ -0,0 +1,160 @@
+mod harness;
+
+use client::{CommandRejected, ConnectionState, LastRejection, PlayerToken};
+use common::auth::{self, TokenClaims};
+use common::bevy::app::Events;
+use common::bevy::prelude::*;
+use common::bevy_networking_turbulence::NetworkResource;
+use common::errors::DisconnectReason;
+use common::errors::PlayerCommandValidationError;
+use common::events::{PlayerCommand, Tick};
+use common::game::GameTick;
+use common::validation::{CommandContext, CommandRule};
+use harness::{pointers, Harness};
+use common::ids::IdAllocator;
+use server::{AcceptedCommand, Kick, ServerAppExt, ServerConfig};
+
+/// Keeps pointers on the right half of the world.
+struct RightHalfOnly;
+
+impl CommandRule for RightHalfOnly {
+    fn check(&mut self, command: &PlayerCommand, _: &CommandContext) -> Result<(), PlayerCommandValidationError> {
+        let PlayerCommand::PointerMoveChange(_, order, _) = command;
+        if order.target.x < 640.0 {
+            Err(PlayerCommandValidationError::OutOfBounds { x: order.target.x, y: order.target.y })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Default)]
+struct AcceptedCount(usize);
+
+#[derive(Default)]
+struct SeenTicks(Vec<Tick>);
+
+#[derive(Default)]
+struct Rejections(Vec<PlayerCommandValidationError>);
+
+fn collect_rejections(mut rejected: EventReader<CommandRejected>, mut rejections: ResMut<Rejections>) {
+    rejections.0.extend(rejected.iter().map(|CommandRejected(_, reason)| reason.clone()));
+}
+
+fn count_accepted(mut accepted: EventReader<AcceptedCommand>, mut count: ResMut<AcceptedCount>) {
+    count.0 += accepted.iter().count();
+}
+
+#[test]
+fn command_rules_added_to_the_server_reject_commands() {
+    let mut harness = Harness::with_apps(
+        1,
+        |server| {
+            server.add_command_rule(RightHalfOnly);
+        },
+        |client| {
+            client.init_resource::<Rejections>().add_system(collect_rejections.system());
+        },
+    );
+    harness.connect_all();
+    assert!(harness.run_until(500, |harness| !pointers(&mut harness.clients[0]).is_empty()));
+
+    harness.click(0, Vec2::new(100.0, 100.0));
+    let rejected = harness.run_until(500, |harness| {
+        !harness.clients[0].world.get_resource::<Rejections>().unwrap().0.is_empty()
+    });
+    assert!(rejected, "the command was not rejected");
+    let rejections = &harness.clients[0].world.get_resource::<Rejections>().unwrap().0;
+    assert_eq!(rejections[0], PlayerCommandValidationError::OutOfBounds { x: 100.0, y: 100.0 });
+    // shown in the window title, for clients that have one
+    let shown = &harness.clients[0].world.get_resource::<LastRejection>().unwrap().0;
+    assert_eq!(shown.as_ref(), Some(&rejections[0]));
+}
+
+fn record_tick(tick: Res<GameTick>, mut seen: ResMut<SeenTicks>) {
+    seen.0.push(tick.0);
+}
+
+#[test]
+fn game_systems_run_once_per_tick_before_it_advances() {
+    let mut harness = Harness::with_server(0, |server| {
+        server.init_resource::<SeenTicks>().add_game_system(record_tick.system());
+    });
+    for _ in 0..10 {
+        harness.step();
+    }
+
+    let seen = &harness.server.world.get_resource::<SeenTicks>().unwrap().0;
+    assert_eq!(seen.len(), 10, "the game system did not run once per step");
+    assert!(seen.windows(2).all(|pair| pair[1] == pair[0] + 1), "ticks were skipped or repeated: {:?}", seen);
+    assert_eq!(*seen.last().unwrap() + 1, harness.server.world.get_resource::<GameTick>().unwrap().0);
+}
+
+#[test]
+fn accepted_commands_are_announced() {
+    let mut harness = Harness::with_server(1, |server| {
+        server.init_resource::<AcceptedCount>().add_system(count_accepted.system());
+    });
+    harness.connect_all();
+    assert!(harness.run_until(500, |harness| !pointers(&mut harness.clients[0]).is_empty()));
+
+    harness.click(0, Vec2::new(700.0, 100.0));
+    let counted = harness.run_until(500, |harness| {
+        harness.server.world.get_resource::<AcceptedCount>().unwrap().0 == 1
+    });
+    assert!(counted, "the accepted command was not announced");
+}
+
+#[test]
+fn commands_over_the_rate_limit_are_rejected() {
+    let mut harness = Harness::with_apps(
+        1,
+        |server| {
+            let mut config = server.world_mut().get_resource_mut::<ServerConfig>().unwrap();
+            config.rate_limit.commands_per_second = 0.1;
+            config.rate_limit.command_burst = 1.0;
+        },
+        |client| {
+            client.init_resource::<Rejections>().add_system(collect_rejections.system());
+        },
+    );
+    harness.connect_all();
+    assert!(harness.run_until(500, |harness| !pointers(&mut harness.clients[0]).is_empty()));
+
+    // the second one goes over the budget, and has to be rolled back like any other rejection
+    harness.click(0, Vec2::new(700.0, 100.0));
+    harness.click(0, Vec2::new(800.0, 100.0));
+    let rejected = harness.run_until(500, |harness| {
+        !harness.clients[0].world.get_resource::<Rejections>().unwrap().0.is_empty()
+    });
+    assert!(rejected, "the command over the limit was not rejected");
+    let rejections = &harness.clients[0].world.get_resource::<Rejections>().unwrap().0;
+    assert_eq!(rejections, &vec![PlayerCommandValidationError::RateExceeded { per_second: 0.1 }]);
+}
+
+#[test]
+fn player_ids_from_tokens_are_not_released_into_the_pool() {
+    let claims = TokenClaims { player_id: 42, name: "alice".to_string(), expires_at: None };
+    let token = auth::sign(&claims, b"secret");
+    let mut harness = Harness::with_apps(
+        1,
+        |server| {
+            server.world_mut().get_resource_mut::<ServerConfig>().unwrap().auth_secret = Some("secret".to_string());
+        },
+        |client| {
+            client.insert_resource(PlayerToken(Some(token.clone())));
+        },
+    );
+    harness.connect_all();
+    assert_eq!(harness.player_id(0), 42);
+
+    let handle = *harness.server.world.get_resource::<NetworkResource>().unwrap().connections.keys().next().unwrap();
+    let kick = Kick(handle, DisconnectReason::Kicked("test".to_string()));
+    harness.server.world.get_resource_mut::<Events<Kick>>().unwrap().send(kick);
+    let kicked = harness.run_until(500, |harness| {
+        matches!(harness.clients[0].world.get_resource::<ConnectionState>().unwrap(), ConnectionState::Disconnected(Some(_)))
+    });
+    assert!(kicked, "the client was not kicked");
+    assert!(harness.run_until(500, |harness| pointers(&mut harness.server).is_empty()), "the player was not removed");
+    assert_eq!(harness.server.world.get_resource::<IdAllocator>().unwrap().released_players(), 0);
+}
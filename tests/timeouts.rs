@@ -0,0 +1,179 @@
+mod harness;
+
+use client::ConnectionState;
+use common::bevy::app::{Events, ManualEventReader};
+use common::bevy::prelude::*;
+use common::bevy_networking_turbulence::{NetworkError, NetworkEvent, NetworkResource};
+use common::errors::DisconnectReason;
+use common::ids::IdAllocator;
+use common::protocol::{network_setup, ClientIdentification, ConnectionPlugin, Hello, MetaInformation, NetworkConfig};
+use harness::{keep, pointers, wait, Harness};
+use server::ServerConfig;
+use std::mem::ManuallyDrop;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Turbulence times idle connections on the wall clock, so these tests have to wait for real.
+fn test_config() -> NetworkConfig {
+    NetworkConfig {
+        idle_timeout_ms: 500,
+        heartbeat_ms: 100,
+        check_interval: 0.05,
+    }
+}
+
+fn idle_timeout(config: &NetworkConfig) -> Duration {
+    Duration::from_millis(config.idle_timeout_ms as u64)
+}
+
+fn free_address() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn peer(config: &NetworkConfig) -> ManuallyDrop<App> {
+    let mut builder = App::build();
+    builder
+        .add_plugins(MinimalPlugins)
+        .add_plugin(ConnectionPlugin { config: config.clone() });
+    let mut app = builder.app;
+    network_setup(&mut app.world.get_resource_mut::<NetworkResource>().unwrap());
+    keep(app)
+}
+
+fn server(config: &NetworkConfig, address: SocketAddr) -> ManuallyDrop<App> {
+    let mut app = peer(config);
+    app.world.get_resource_mut::<NetworkResource>().unwrap().listen(address, None, None);
+    app
+}
+
+/// Connects and says hello, so the server knows about the client.
+fn client(config: &NetworkConfig, address: SocketAddr) -> ManuallyDrop<App> {
+    let mut app = peer(config);
+    let mut net = app.world.get_resource_mut::<NetworkResource>().unwrap();
+    net.connect(address);
+    app.update();
+    app.world
+        .get_resource_mut::<NetworkResource>()
+        .unwrap()
+        .broadcast_message(MetaInformation::Hello(Hello::current()));
+    app
+}
+
+/// Network events `app` raised since the last call with the same reader.
+fn events(app: &App, reader: &mut ManualEventReader<NetworkEvent>) -> Vec<String> {
+    let events = app.world.get_resource::<Events<NetworkEvent>>().unwrap();
+    reader
+        .iter(events)
+        .map(|event| match event {
+            NetworkEvent::Connected(_) => "connected".to_string(),
+            NetworkEvent::Disconnected(_) => "disconnected".to_string(),
+            NetworkEvent::Error(_, NetworkError::MissedHeartbeat) => "missed heartbeat".to_string(),
+            other => format!("{:?}", other),
+        })
+        .collect()
+}
+
+fn connections(app: &App) -> usize {
+    app.world.get_resource::<NetworkResource>().unwrap().connections.len()
+}
+
+#[test]
+fn silent_client_is_dropped() {
+    let config = test_config();
+    let address = free_address();
+    let mut server = server(&config, address);
+    let mut reader = ManualEventReader::<NetworkEvent>::default();
+    let mut seen = Vec::new();
+
+    // a client that sends a single heartbeat and then hangs
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    silent.send_to(&[], address).unwrap();
+    let heard_at = Instant::now();
+
+    let connected = wait(&mut [&mut server], idle_timeout(&config), |apps| {
+        seen.extend(events(apps[0], &mut reader));
+        !seen.is_empty()
+    });
+    assert!(connected);
+    assert_eq!(seen, vec!["connected"]);
+
+    seen.clear();
+    let dropped = wait(&mut [&mut server], 10 * idle_timeout(&config), |apps| {
+        seen.extend(events(apps[0], &mut reader));
+        seen.len() >= 2
+    });
+    assert!(dropped, "the silent client was never dropped");
+    assert!(heard_at.elapsed() >= idle_timeout(&config), "dropped before the idle timeout");
+    assert_eq!(seen, vec!["missed heartbeat", "disconnected"]);
+    assert_eq!(connections(&server), 0);
+}
+
+#[test]
+fn idle_client_is_kept_alive_by_heartbeats() {
+    let config = test_config();
+    let address = free_address();
+    let mut server = server(&config, address);
+    let mut client = client(&config, address);
+    let mut reader = ManualEventReader::<NetworkEvent>::default();
+    let mut seen = Vec::new();
+
+    let connected = wait(&mut [&mut server, &mut client], idle_timeout(&config), |apps| {
+        seen.extend(events(apps[0], &mut reader));
+        !seen.is_empty()
+    });
+    assert!(connected);
+    assert_eq!(seen, vec!["connected"]);
+
+    // nothing but heartbeats travels after the hello, for several idle timeouts
+    let dropped = wait(&mut [&mut server, &mut client], 3 * idle_timeout(&config), |apps| {
+        seen.extend(events(apps[0], &mut reader));
+        connections(apps[0]) == 0
+    });
+    assert!(!dropped);
+    assert_eq!(seen, vec!["connected"]);
+    assert_eq!(connections(&server), 1);
+}
+
+#[test]
+fn timed_out_player_resumes_their_session() {
+    let config = test_config();
+    let mut harness = Harness::with_network(1, config.clone());
+    harness.connect_all();
+    let player_id = harness.player_id(0);
+
+    // the client hangs, the server drops it but keeps the player around
+    let dropped = wait(&mut [&mut harness.server], 10 * idle_timeout(&config), |apps| connections(apps[0]) == 0);
+    assert!(dropped, "the silent client was never dropped");
+    assert_eq!(pointers(&mut harness.server).len(), 1, "the pointer went away with the connection");
+
+    // once it runs again its heartbeats open a new connection the server knows nothing about, the client
+    // notices the server stopped pinging and reconnects into the same session
+    let mut lost = false;
+    let resumed = wait(&mut harness.apps().collect::<Vec<_>>(), Duration::from_secs(10), |apps| {
+        let state = apps[1].world.get_resource::<ConnectionState>().unwrap();
+        lost |= *state == ConnectionState::Disconnected(Some(DisconnectReason::Timeout));
+        lost && *state == ConnectionState::Connected && connections(apps[0]) == 1
+    });
+    assert!(resumed, "the client did not come back");
+    assert_eq!(harness.clients[0].world.get_resource::<ClientIdentification>().unwrap().player_id, player_id);
+    assert_eq!(pointers(&mut harness.server).len(), 1);
+    assert_eq!(harness.server.world.get_resource::<IdAllocator>().unwrap().released_players(), 0);
+}
+
+#[test]
+fn timed_out_player_is_removed_after_the_grace() {
+    let config = test_config();
+    let mut harness = Harness::with_network(1, config.clone());
+    harness.server.world.get_resource_mut::<ServerConfig>().unwrap().session_grace = 0.5;
+    harness.connect_all();
+    assert_eq!(pointers(&mut harness.server).len(), 1);
+
+    let dropped = wait(&mut [&mut harness.server], 10 * idle_timeout(&config), |apps| connections(apps[0]) == 0);
+    assert!(dropped, "the silent client was never dropped");
+    let dropped_at = Instant::now();
+
+    let removed = wait(&mut [&mut harness.server], Duration::from_secs(5), |apps| pointers(apps[0]).is_empty());
+    assert!(removed, "the pointer outlived the grace");
+    assert!(dropped_at.elapsed() >= Duration::from_millis(400), "removed before the grace was over");
+    assert_eq!(harness.server.world.get_resource::<IdAllocator>().unwrap().released_players(), 1);
+}